@@ -0,0 +1,309 @@
+use std::fmt;
+use std::fs::{canonicalize, copy, create_dir_all, hard_link, metadata, remove_file, rename, set_permissions, symlink_metadata, File};
+use std::io;
+use std::io::{BufReader, Read};
+use std::os::unix::fs::{lchown, symlink, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::{reflink, trash, Error, FileEntry, Result};
+
+// What is done to duplicate files
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    // Remove duplicate file
+    Delete,
+    // Replace duplicate file with a hard link to the kept file
+    HardLink,
+    // Share the data of duplicate and kept file with copy-on-write (reflink), both files stay independent
+    Reflink,
+    // Replace duplicate file with a symbolic link to the kept file's absolute path
+    Symlink,
+    // Replace duplicate file with a symbolic link to the kept file relative to the duplicate's directory
+    RelativeSymlink,
+    // Move duplicate file into quarantine directory, mirroring its absolute path there
+    Move {
+        dir: PathBuf,
+    },
+    // Move duplicate file into the freedesktop.org trash so it can be restored from file managers
+    Trash,
+}
+
+impl Action {
+    pub const ALL: [Action; 6] = [
+        Action::Delete,
+        Action::HardLink,
+        Action::Reflink,
+        Action::Symlink,
+        Action::RelativeSymlink,
+        Action::Trash,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Delete => "delete",
+            Action::HardLink => "hard-link",
+            Action::Reflink => "reflink",
+            Action::Symlink => "symlink",
+            Action::RelativeSymlink => "relative-symlink",
+            Action::Move { .. } => "move",
+            Action::Trash => "trash",
+        }
+    }
+
+    // Apply action to a duplicate of the kept file,
+    // returns the new location of the duplicate if it was moved.
+    // Both files are checked to be unchanged since scanning and to be different files first.
+    pub fn apply(&self, keep: &FileEntry, duplicate: &FileEntry) -> Result<Option<PathBuf>> {
+        let path = &duplicate.path;
+
+        keep.check()?;
+        duplicate.check()?;
+
+        // Checks above make sure the current identities are the scanned ones
+        if keep.id == duplicate.id {
+            return Err(Error::SameFile {
+                path: path.to_path_buf(),
+                keep: keep.path.to_path_buf(),
+            });
+        }
+
+        match self {
+            Action::Delete => {
+                remove_file(path).map_err(|e| self.error(path, e))?;
+            }
+            Action::HardLink => {
+                if keep.id.dev != duplicate.id.dev {
+                    return Err(Error::CrossDevice {
+                        path: path.to_path_buf(),
+                        keep: keep.path.to_path_buf(),
+                    });
+                }
+
+                replace_with(path, |tmp| hard_link(&keep.path, tmp))
+                    .map_err(|e| self.error(path, e))?;
+            }
+            Action::Reflink => {
+                match reflink::dedupe(&keep.path, path) {
+                    Ok(()) => {}
+                    Err(e) if reflink::is_unsupported(&e) => {
+                        // Some file systems can clone but not deduplicate,
+                        // replace duplicate with a clone of the kept file keeping owner, permissions and mtime
+                        let m = metadata(path).map_err(|e| self.error(path, e))?;
+
+                        replace_with(path, |tmp| {
+                            reflink::clone(&keep.path, tmp)?;
+                            lchown(tmp, Some(m.uid()), Some(m.gid()))?;
+                            set_permissions(tmp, m.permissions())?;
+                            File::options().write(true).open(tmp)?.set_modified(m.modified()?)
+                        }).map_err(|e| {
+                            if reflink::is_unsupported(&e) {
+                                Error::Unsupported {
+                                    path: path.to_path_buf(),
+                                    action: self.clone(),
+                                }
+                            } else {
+                                self.error(path, e)
+                            }
+                        })?;
+                    }
+                    Err(e) => return Err(self.error(path, e)),
+                }
+            }
+            Action::Symlink | Action::RelativeSymlink => {
+                let mut target = canonicalize(&keep.path).map_err(|e| self.error(&keep.path, e))?;
+
+                if *self == Action::RelativeSymlink {
+                    let dir = path.parent().unwrap_or(Path::new("."));
+                    let dir = canonicalize(dir).map_err(|e| self.error(dir, e))?;
+                    target = relative_path(&dir, &target);
+                }
+
+                replace_with(path, |tmp| symlink(&target, tmp))
+                    .map_err(|e| self.error(path, e))?;
+            }
+            Action::Move { dir } => {
+                let from = canonicalize(path).map_err(|e| self.error(path, e))?;
+                let to = quarantine_path(dir, &from);
+
+                if let Some(parent) = to.parent() {
+                    create_dir_all(parent).map_err(|e| self.error(parent, e))?;
+                }
+
+                if symlink_metadata(&to).is_ok() {
+                    return Err(self.error(&to, io::ErrorKind::AlreadyExists.into()));
+                }
+
+                move_file(&from, &to).map_err(|e| self.error(path, e))?;
+                return Ok(Some(to));
+            }
+            Action::Trash => {
+                let to = trash::trash(path).map_err(|e| self.error(path, e))?;
+                return Ok(Some(to));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn error(&self, path: &Path, source: io::Error) -> Error {
+        Error::Action {
+            path: path.to_path_buf(),
+            action: self.clone(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match Action::ALL.iter().find(|a| a.name() == s) {
+            Some(a) => Ok(a.clone()),
+            None => Err(format!("unknown action: {}", s)),
+        }
+    }
+}
+
+// Path to target relative to directory dir, both must be absolute
+fn relative_path(dir: &Path, target: &Path) -> PathBuf {
+    let dir: Vec<Component> = dir.components().collect();
+    let target: Vec<Component> = target.components().collect();
+    let common = dir.iter().zip(target.iter()).take_while(|(a, b)| a == b).count();
+
+    let mut p = PathBuf::new();
+
+    for _ in common..dir.len() {
+        p.push("..");
+    }
+
+    for c in &target[common..] {
+        p.push(c);
+    }
+
+    p
+}
+
+// Path of file inside quarantine directory, file path must be absolute
+fn quarantine_path(dir: &Path, path: &Path) -> PathBuf {
+    let mut p = dir.to_path_buf();
+
+    for c in path.components() {
+        if let Component::Normal(c) = c {
+            p.push(c);
+        }
+    }
+
+    p
+}
+
+// Move file, across file systems by copying, verifying the copy and removing the original
+pub(crate) fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match rename(from, to) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {}
+        r => return r,
+    }
+
+    copy_verified(from, to, |from, tmp| copy(from, tmp).map(|_| ()))?;
+    remove_file(from)
+}
+
+// Copy file to a temporary name next to target with given function, keep mtime,
+// verify the bytes against the original and rename the copy to target
+pub(crate) fn copy_verified<F: FnOnce(&Path, &Path) -> io::Result<()>>(from: &Path, to: &Path, copy: F) -> io::Result<()> {
+    let tmp = temp_path(to);
+
+    let r = copy(from, &tmp)
+        .and_then(|_| {
+            let modified = metadata(from)?.modified()?;
+            File::options().write(true).open(&tmp)?.set_modified(modified)
+        })
+        .and_then(|_| {
+            if same_contents(from, &tmp)? {
+                Ok(())
+            } else {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "copy differs from original"))
+            }
+        })
+        .and_then(|_| rename(&tmp, to));
+
+    if let Err(e) = r {
+        let _ = remove_file(&tmp);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+// Compare two files byte by byte
+pub(crate) fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut fa = BufReader::new(File::open(a)?);
+    let mut fb = BufReader::new(File::open(b)?);
+
+    let mut ba = vec![0u8; 1048576];
+    let mut bb = vec![0u8; 1048576];
+
+    loop {
+        let count = read_full(&mut fa, &mut ba)?;
+        if count != read_full(&mut fb, &mut bb)? {
+            return Ok(false);
+        }
+
+        if count == 0 {
+            return Ok(true);
+        }
+
+        if ba[..count] != bb[..count] {
+            return Ok(false);
+        }
+    }
+}
+
+// Read until buffer is full or end of file
+pub(crate) fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut count = 0;
+
+    while count < buf.len() {
+        match r.read(&mut buf[count..]) {
+            Ok(0) => break,
+            Ok(n) => count += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(count)
+}
+
+// Temporary name in the same directory as the file
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".samanlainen-{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+// Atomically replace file: create the replacement under a temporary name with given function,
+// then rename it over the file
+pub(crate) fn replace_with<F: FnOnce(&Path) -> io::Result<()>>(path: &Path, create: F) -> io::Result<()> {
+    let tmp = temp_path(path);
+
+    if let Err(e) = create(&tmp) {
+        let _ = remove_file(&tmp);
+        return Err(e);
+    }
+
+    if let Err(e) = rename(&tmp, path) {
+        let _ = remove_file(&tmp);
+        return Err(e);
+    }
+
+    Ok(())
+}
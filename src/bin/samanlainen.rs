@@ -1,22 +1,23 @@
 use std::{cmp, io};
-use std::collections::HashMap;
-use std::fs::{canonicalize, remove_file};
+use std::fs::canonicalize;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
+use std::time::SystemTime;
 
-use atty;
 use clap::error::ErrorKind;
 use clap::Parser;
 use parse_size::parse_size;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 use samanlainen::{
-    eliminate_first_or_last_bytes_hash, find_candidate_files, find_final_candidates,
-    generate_stats, ScanType,
+    parse_pipeline, Action, DeviceReaders, DuplicateFinder, Error, HashAlgorithm, Journal, JournalEntry, KeepPolicy, KeepRule, PartialStage, Progress,
+    RestoreOutcome, ScanType, SortOrder, Stage, MAX_COMPARE,
 };
 
 #[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 enum ConvertTo {
     // 1000
     SI,
@@ -76,7 +77,7 @@ fn convert_bytes(bytes: u64, conv: ConvertTo) -> String {
 fn parse_min_bytes(s: &str) -> Result<u64, clap::Error> {
     let min: u64 = match parse_size(s) {
         Ok(r) => r,
-        Err(ref e) => return Err(clap::Error::raw(ErrorKind::InvalidValue, "invalid value")),
+        Err(_) => return Err(clap::Error::raw(ErrorKind::InvalidValue, "invalid value")),
     };
 
     if min < 1 {
@@ -89,7 +90,7 @@ fn parse_min_bytes(s: &str) -> Result<u64, clap::Error> {
 fn parse_max_bytes(s: &str) -> Result<u64, clap::Error> {
     let max: u64 = match parse_size(s) {
         Ok(r) => r,
-        Err(ref e) => return Err(clap::Error::raw(ErrorKind::InvalidValue, "invalid value")),
+        Err(_) => return Err(clap::Error::raw(ErrorKind::InvalidValue, "invalid value")),
     };
 
     if max < 1 {
@@ -102,7 +103,7 @@ fn parse_max_bytes(s: &str) -> Result<u64, clap::Error> {
 fn parse_scansize_bytes(s: &str) -> Result<u64, clap::Error> {
     let ss = match parse_size(s) {
         Ok(r) => r,
-        Err(ref e) => return Err(clap::Error::raw(ErrorKind::InvalidValue, "invalid value")),
+        Err(_) => return Err(clap::Error::raw(ErrorKind::InvalidValue, "invalid value")),
     };
 
     if ss < 1 {
@@ -112,11 +113,38 @@ fn parse_scansize_bytes(s: &str) -> Result<u64, clap::Error> {
     Ok(ss)
 }
 
+fn parse_compare(s: &str) -> Result<usize, clap::Error> {
+    let n: usize = match s.parse() {
+        Ok(r) => r,
+        Err(_) => return Err(clap::Error::raw(ErrorKind::InvalidValue, "invalid value")),
+    };
+
+    if n > MAX_COMPARE {
+        return Err(clap::Error::raw(ErrorKind::ValueValidation, format!("maximum is {} for compare", MAX_COMPARE)));
+    }
+
+    Ok(n)
+}
+
+fn parse_hash(s: &str) -> Result<HashAlgorithm, String> {
+    let hash = HashAlgorithm::from_str(s)?;
+
+    if !hash.is_cryptographic() {
+        return Err(format!("{} is not a cryptographic hash, it can only be used with --partial-hash", hash));
+    }
+
+    Ok(hash)
+}
+
 // CLI arguments
 // See: https://docs.rs/clap/latest/clap/
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None,
+subcommand_negates_reqs = true)]
 struct CLIArgs {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(short = 'm', long, default_value = "1B",
     help = "Minimum filesize to scan, supports EIC/SI units",
     value_parser = parse_min_bytes)]
@@ -133,13 +161,92 @@ struct CLIArgs {
     count: u64,
 
     #[clap(short = 's', long, default_value = "1MiB",
-    help = "Scan size used for scanning first and last bytes of file, supports EIC/SI units",
+    help = "Scan size used by partial hashing stages which don't set their own size, supports EIC/SI units",
     value_parser = parse_scansize_bytes)]
     scansize: u64,
 
-    #[clap(long, help = "Delete files? If enabled, files are actually deleted")]
+    #[clap(long,
+    help = "Derive the scan size of partial hashing stages which don't set their own size from the file size and number of files of each size group")]
+    adaptive_scansize: bool,
+
+    #[clap(short = 'H', long, default_value = "sha512",
+    help = "Hash algorithm for full hashing [possible values: sha512, sha256, blake3]",
+    value_parser = parse_hash)]
+    hash: HashAlgorithm,
+
+    #[clap(long, default_value = "sha512",
+    help = "Hash algorithm for partial hashing stages, xxh3 is fast but not cryptographic [possible values: sha512, sha256, blake3, xxh3]",
+    value_parser = HashAlgorithm::from_str)]
+    partial_hash: HashAlgorithm,
+
+    #[clap(long, default_value = "0", value_name = "N",
+    help = "Compare size groups of at most N files (max. 64) block by block in lockstep instead of hashing them fully, 0 hashes all groups",
+    value_parser = parse_compare)]
+    compare: usize,
+
+    #[clap(long, default_value = "size,last,first,full",
+    help = "Comma separated elimination pipeline from size grouping to full hashing, partial stages are first, last, middle and sample:N (N evenly spaced blocks) with optional scan size, e.g. size,first:4KiB,last:1MiB,sample:8x64KiB,full",
+    value_parser = parse_pipeline)]
+    stages: ::std::vec::Vec<PartialStage>,
+
+    #[clap(short = 'k', long, default_value = "priority,oldest", value_delimiter = ',',
+    help = "Comma separated rules for choosing the file to keep, later rules break ties [possible values: priority, oldest, newest, shortest-path, shallowest, name, most-links]",
+    value_parser = KeepRule::from_str)]
+    keep: Vec<KeepRule>,
+
+    #[clap(short = 'S', long, default_value = "i-node",
+    help = "Sort order of directory entries while scanning [possible values: i-node, filename, depth]",
+    value_parser = SortOrder::from_str)]
+    sort_order: SortOrder,
+
+    #[clap(short = 't', long, default_value = "0",
+    help = "Number of threads hashing files, 0 uses one thread per CPU core")]
+    threads: usize,
+
+    #[clap(long, default_value = "1", value_name = "N",
+    help = "Number of files read at the same time from each rotational hard drive, 0 means no limit")]
+    hdd_readers: usize,
+
+    #[clap(long, default_value = "0", value_name = "N",
+    help = "Number of files read at the same time from each SSD or other device, 0 means no limit")]
+    ssd_readers: usize,
+
+    #[clap(long,
+    help = "Read files on rotational hard drives in the order of their data on disk (FIEMAP) to reduce seeking")]
+    physical_order: bool,
+
+    #[clap(short = 'v', long, action = clap::ArgAction::Count,
+    help = "Be verbose, -v lists eliminated files, -vv also skipped files, -vvv also checksums")]
+    verbose: u8,
+
+    #[clap(short = 'a', long, default_value = "delete",
+    help = "Action for duplicate files [possible values: delete, hard-link, reflink, symlink, relative-symlink, trash]",
+    value_parser = Action::from_str)]
+    action: Action,
+
+    #[clap(long,
+    help = "Action used for files when the file system doesn't support --action, for example reflinks",
+    value_parser = Action::from_str)]
+    fallback: Option<Action>,
+
+    #[clap(long, value_name = "DIR", conflicts_with = "action",
+    help = "Move duplicate files into quarantine directory DIR, mirroring their original paths")]
+    move_to: Option<PathBuf>,
+
+    #[clap(long,
+    help = "Compare each duplicate byte by byte against the kept file before the action, groups with differences are skipped")]
+    paranoid: bool,
+
+    #[clap(short = 'j', long, value_name = "FILE",
+    help = "Append every action taken to journal FILE, used by the restore command")]
+    journal: Option<PathBuf>,
+
+    #[clap(long, help = "Delete files? If enabled, the action is actually applied to duplicate files")]
     delete_files: bool,
 
+    #[clap(long, help = "Skip files and directories which can't be read instead of aborting, errors are listed at the end")]
+    skip_errors: bool,
+
     #[clap(short = 'C', long, value_enum, help = "Color", default_value = "auto")]
     color: ColorMode,
 
@@ -149,6 +256,15 @@ struct CLIArgs {
     paths: Vec<PathBuf>,
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    #[clap(about = "Restore files removed, replaced or moved by earlier runs using their journal")]
+    Restore {
+        #[clap(help = "Journal file written with --journal")]
+        journal: PathBuf,
+    },
+}
+
 fn get_directories(dirs: Vec<PathBuf>) -> Result<Vec<PathBuf>, String> {
     let mut found_dirs: Vec<PathBuf> = Vec::new(); // for possible duplicates
     let mut dirs_to_search: Vec<PathBuf> = Vec::new();
@@ -199,6 +315,10 @@ fn main() -> Result<(), io::Error> {
     let mut stderr = StandardStream::stderr(color_choice);
     set_color(&mut stderr, ERR_COLOR);
 
+    if let Some(Command::Restore { journal }) = args.command {
+        exit(restore(&journal, &mut stdout, &mut stderr));
+    }
+
     if args.minsize > args.maxsize {
         writeln!(&mut stderr, "minsize is larger than maxsize").expect("");
         exit(1);
@@ -222,17 +342,50 @@ fn main() -> Result<(), io::Error> {
         exit(0);
     }
 
+    let action = match args.move_to {
+        Some(dir) => match std::path::absolute(&dir) {
+            Ok(dir) => Action::Move { dir },
+            Err(e) => {
+                writeln!(&mut stderr, "could not parse quarantine directory: {}", e).expect("");
+                exit(1);
+            }
+        },
+        None => args.action,
+    };
+
     set_color(&mut stdout, ERR_COLOR);
 
     if args.delete_files {
-        writeln!(&mut stdout, "WARNING: deleting files!").expect("");
+        writeln!(&mut stdout, "WARNING: {} files!", action_verb(&action)).expect("");
     } else {
         writeln!(
             &mut stdout,
-            "Not deleting files (dry run), add --delete-files to actually delete files."
+            "Not {} files (dry run), add --delete-files to actually {} files.",
+            action_verb(&action),
+            action
         ).expect("");
     }
 
+    if let Action::Move { dir } = &action {
+        writeln!(&mut stdout, "Quarantine directory: {}", dir.display()).expect("");
+    }
+
+    let mut journal: Option<Journal> = None;
+
+    if let Some(path) = &args.journal {
+        writeln!(&mut stdout, "Journal: {}", path.display()).expect("");
+
+        if args.delete_files {
+            journal = match Journal::create(path) {
+                Ok(j) => Some(j),
+                Err(e) => {
+                    writeln!(&mut stderr, "ERROR: {}", e).expect("");
+                    exit(1);
+                }
+            };
+        }
+    }
+
     set_color(&mut stdout, Some(Color::Rgb(128, 128, 0)));
 
     writeln!(
@@ -244,171 +397,311 @@ fn main() -> Result<(), io::Error> {
 
     writeln!(
         &mut stdout,
-        "Scan size for last and first bytes of files: {}",
-        convert_to_human(args.scansize)
+        "Stages: size,{}full  Scan size: {}",
+        args.stages.iter().map(|p| format!("{},", p)).collect::<String>(),
+        match args.adaptive_scansize {
+            true => "adaptive per size group".to_string(),
+            false => convert_to_human(args.scansize),
+        }
     ).expect("");
 
+    writeln!(&mut stdout, "Hash: {}  Partial hash: {}", args.hash, args.partial_hash).expect("");
+
+    if args.verbose > 0 {
+        writeln!(&mut stdout, "Sort order: {}", args.sort_order).expect("");
+        writeln!(&mut stdout, "Hashing threads: {}", match args.threads {
+            0 => "one per CPU core".to_string(),
+            n => n.to_string(),
+        }).expect("");
+        writeln!(&mut stdout, "Readers per device: {}", DeviceReaders {
+            rotational: args.hdd_readers,
+            other: args.ssd_readers,
+        }).expect("");
+        writeln!(
+            &mut stdout,
+            "Keep rules: {}",
+            args.keep.iter().map(|r| r.name()).collect::<Vec<&str>>().join(", ")
+        ).expect("");
+    }
+
     writeln!(&mut stdout, "Directories to scan:").expect("");
     set_color(&mut stdout, Some(Color::Rgb(255, 255, 0)));
     for dir in dirs_to_search.clone() {
         writeln!(&mut stdout, " * {}", dir.display()).expect("");
     }
 
-    writeln!(&mut stdout, "").expect("");
+    writeln!(&mut stdout).expect("");
 
     set_color(&mut stdout, DEFAULT_COLOR);
 
-    writeln!(
-        &mut stdout,
-        "(1 / 6) Generating file list based on file sizes..."
-    ).expect("");
-
-    let mut files_found: HashMap<u64, Vec<PathBuf>> =
-        find_candidate_files(dirs_to_search, args.minsize, args.maxsize, args.count)?;
-    let (file_count, total_size) = generate_stats(files_found.to_owned());
-
-    set_color(&mut stdout, STATS_COLOR);
-    writeln!(
-        &mut stdout,
-        "  File candidates: {} Total size: {}",
-        file_count,
-        convert_to_human(total_size)
-    ).expect("");
-    set_color(&mut stdout, DEFAULT_COLOR);
-
-    if files_found.is_empty() {
-        writeln!(&mut stdout, "No files.").expect("");
-        exit(0);
-    }
-
-    // Scan last bytes
-    writeln!(
-        &mut stdout,
-        "(2 / 6) Eliminating candidates based on last {} bytes of files  Total scan: {}...",
-        convert_to_human(args.scansize),
-        convert_to_human(file_count * args.scansize),
-    ).expect("");
-
-    files_found = eliminate_first_or_last_bytes_hash(
-        files_found.to_owned(),
-        ScanType::Last,
-        args.scansize,
-        args.count,
-    )?;
-    let (file_count, total_size) = generate_stats(files_found.to_owned());
-
-    set_color(&mut stdout, STATS_COLOR);
-    writeln!(
-        &mut stdout,
-        "  File candidates: {} Total size: {}",
-        file_count,
-        convert_to_human(total_size)
-    ).expect("");
+    let finder = DuplicateFinder::new()
+        .paths(dirs_to_search)
+        .minimum_size(args.minsize)
+        .maximum_size(args.maxsize)
+        .count(args.count)
+        .scansize(args.scansize)
+        .adaptive_scansize(args.adaptive_scansize)
+        .stages(args.stages.clone())
+        .skip_errors(args.skip_errors)
+        .keep(KeepPolicy::new(args.keep))
+        .sort_order(args.sort_order)
+        .threads(args.threads)
+        .readers(DeviceReaders {
+            rotational: args.hdd_readers,
+            other: args.ssd_readers,
+        })
+        .physical_order(args.physical_order)
+        .hash(args.hash)
+        .partial_hash(args.partial_hash)
+        .compare(args.compare);
+
+    // Size stage, partial stages, full hashing, deleting and summary
+    let steps = finder.options().stages.len() + 4;
+    let mut step = 0;
+    let mut partial = 0;
+    let mut adaptive = false;
+    let mut file_count: u64 = 0;
+
+    let report = finder.run_with(|p| match p {
+        Progress::StageStarted(stage) => {
+            set_color(&mut stdout, DEFAULT_COLOR);
+
+            match stage {
+                Stage::Size => {
+                    step += 1;
+                    writeln!(
+                        &mut stdout,
+                        "({} / {}) Generating file list based on file sizes...",
+                        step, steps
+                    ).expect("");
+                }
+                Stage::Partial(t) => {
+                    let scansize = finder.options().stages[partial].size;
+                    partial += 1;
+                    step += 1;
+
+                    let name = match t {
+                        ScanType::First => "first".to_string(),
+                        ScanType::Last => "last".to_string(),
+                        ScanType::Middle => "middle".to_string(),
+                        ScanType::Sampled(n) => format!("{} sampled blocks of", n),
+                    };
+
+                    // Scan size of each size group is reported when the group is scanned
+                    adaptive = scansize.is_none() && args.adaptive_scansize;
+
+                    if adaptive {
+                        writeln!(
+                            &mut stdout,
+                            "({} / {}) Eliminating candidates based on {} bytes of files  Scan size: adaptive per size group...",
+                            step, steps, name
+                        ).expect("");
+                    } else {
+                        let scansize = scansize.unwrap_or(args.scansize);
+                        writeln!(
+                            &mut stdout,
+                            "({} / {}) Eliminating candidates based on {} {} bytes of files  Total scan: {}...",
+                            step,
+                            steps,
+                            name,
+                            convert_to_human(scansize),
+                            convert_to_human(file_count * t.read_size(scansize)),
+                        ).expect("");
+                    }
+                }
+                Stage::Full => {
+                    step += 1;
+                }
+            }
+        }
+        Progress::StageFinished(stats) => {
+            file_count = stats.file_count;
 
-    set_color(&mut stdout, DEFAULT_COLOR);
+            set_color(&mut stdout, STATS_COLOR);
+            writeln!(
+                &mut stdout,
+                "  File candidates: {} Total size: {} Eliminated: {}",
+                stats.file_count,
+                convert_to_human(stats.total_size),
+                stats.eliminated
+            ).expect("");
 
-    if files_found.is_empty() {
-        writeln!(&mut stdout, "No files.").expect("");
-        exit(0);
-    }
+            if stats.stage != Stage::Size {
+                writeln!(
+                    &mut stdout,
+                    "  Read: {} Saved: {}",
+                    convert_to_human(stats.bytes_read),
+                    convert_to_human(stats.bytes_saved)
+                ).expect("");
+            }
 
-    // Scan first bytes
-    writeln!(
-        &mut stdout,
-        "(3 / 6) Eliminating candidates based on first {} bytes of files  Total scan: {}...",
-        convert_to_human(args.scansize),
-        convert_to_human(file_count * args.scansize),
-    ).expect("");
+            set_color(&mut stdout, DEFAULT_COLOR);
+        }
+        Progress::HashingGroup { size, file_count } => {
+            writeln!(
+                &mut stdout,
+                "({} / {}) Hashing {} files with size {}  Total: {}...",
+                step,
+                steps,
+                file_count,
+                convert_to_human(*size),
+                convert_to_human(size * file_count)
+            ).expect("");
+        }
+        Progress::ScanningGroup { scan, size, file_count, scansize } => {
+            if adaptive {
+                writeln!(
+                    &mut stdout,
+                    "({} / {}) Scanning {} files with size {}  Scan size: {}  Total scan: {}...",
+                    step,
+                    steps,
+                    file_count,
+                    convert_to_human(*size),
+                    convert_to_human(*scansize),
+                    convert_to_human(file_count * scan.read_size(*scansize))
+                ).expect("");
+            }
+        }
+        Progress::ComparingGroup { size, file_count } => {
+            writeln!(
+                &mut stdout,
+                "({} / {}) Comparing {} files with size {}  Total: {}...",
+                step,
+                steps,
+                file_count,
+                convert_to_human(*size),
+                convert_to_human(size * file_count)
+            ).expect("");
+        }
+        Progress::Eliminated { stage, path } => {
+            if args.verbose >= 1 {
+                set_color(&mut stdout, STATS_COLOR);
+                writeln!(&mut stdout, "    eliminated ({}): {}", stage, path.display()).expect("");
+                set_color(&mut stdout, DEFAULT_COLOR);
+            }
+        }
+        Progress::Skipped { path, reason } => {
+            if args.verbose >= 2 {
+                set_color(&mut stdout, STATS_COLOR);
+                writeln!(&mut stdout, "    skipped ({}): {}", reason, path.display()).expect("");
+                set_color(&mut stdout, DEFAULT_COLOR);
+            }
+        }
+        Progress::Hashed { stage, path, checksum } => {
+            if args.verbose >= 3 {
+                set_color(&mut stdout, STATS_COLOR);
+                writeln!(&mut stdout, "    {} ({}): {}", checksum, stage, path.display()).expect("");
+                set_color(&mut stdout, DEFAULT_COLOR);
+            }
+        }
+    });
 
-    files_found = eliminate_first_or_last_bytes_hash(
-        files_found.to_owned(),
-        ScanType::First,
-        args.scansize,
-        args.count,
-    )?;
+    let report = match report {
+        Ok(r) => r,
+        Err(e) => {
+            writeln!(&mut stderr, "ERROR: {}", e).expect("");
+            exit(1);
+        }
+    };
 
-    let (file_count, total_size) = generate_stats(files_found.to_owned());
-    set_color(&mut stdout, STATS_COLOR);
-    writeln!(
-        &mut stdout,
-        "  File candidates: {} Total size: {}",
-        file_count,
-        convert_to_human(total_size)
-    ).expect("");
-    set_color(&mut stdout, DEFAULT_COLOR);
+    let mut errors: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
 
-    if files_found.is_empty() {
+    if report.groups.is_empty() {
         writeln!(&mut stdout, "No files.").expect("");
+        print_errors(&mut stderr, &errors);
         exit(0);
     }
 
     let mut freed_space: u64 = 0;
     let mut freed_files: u64 = 0;
-    let mut files_remaining: u64 = file_count;
-    let mut space_remaining: u64 = total_size;
-
-    // remove files in file size groups so that collision with different sized files are less likely
-    for (fsize, files) in files_found {
-        if files.is_empty() {
-            continue;
-        }
+    let mut files_remaining: u64 = report.groups.iter().map(|g| g.files.len() as u64).sum();
+    let mut space_remaining: u64 = report.groups.iter().map(|g| g.size * g.files.len() as u64).sum();
 
-        files_remaining -= files.len() as u64;
-        space_remaining -= fsize * (files.len() as u64);
+    // remove files in duplicate groups, each group shares the same file size and checksum
+    for group in report.groups {
+        files_remaining -= group.files.len() as u64;
+        space_remaining -= group.size * (group.files.len() as u64);
 
         set_color(&mut stdout, DEFAULT_COLOR);
 
         writeln!(
             &mut stdout,
-            "(4 / 6) Hashing {} files with size {}  Total: {}...",
-            files.len(),
-            convert_to_human(fsize),
-            convert_to_human(fsize * (files.len() as u64))
+            "({} / {}) {} duplicate files with {} checksum: {}",
+            steps - 1,
+            steps,
+            capitalize(action_verb(&action)),
+            report.hash,
+            group.checksum
         )
             .expect("");
-        let final_candidates = find_final_candidates(files)?;
 
-        for (checksum, files) in final_candidates {
-            if files.is_empty() {
-                writeln!(&mut stdout, "  There were no files").expect("");
-                continue;
-            }
+        set_color(&mut stdout, Some(Color::Rgb(0, 240, 0)));
+        writeln!(&mut stdout, "   +keeping: {}", group.keep().path.display()).expect("");
 
-            if (files.len() as u64) < args.count {
-                writeln!(
-                    &mut stdout,
-                    "  There were too few files with same checksum ({})",
-                    files.len()
-                )
-                    .expect("");
+        if args.paranoid {
+            // Every duplicate is verified before any of them is touched
+            if let Some(e) = group.duplicates().iter().find_map(|file| group.verify(file).err()) {
+                // Leave the whole group alone
+                writeln!(&mut stderr, "ERROR: {}, skipping the group", e).expect("");
+                errors.push(e.to_string());
                 continue;
             }
+        }
 
-            writeln!(
-                &mut stdout,
-                "(5 / 6) Deleting duplicate files with checksum: {}",
-                checksum
-            )
-                .expect("");
-
-            for (i, file) in files.iter().enumerate() {
-                if i == 0 {
-                    // Keep first
-                    set_color(&mut stdout, Some(Color::Rgb(0, 240, 0)));
-                    writeln!(&mut stdout, "   +keeping: {}", file.display()).expect("");
-                    continue;
-                }
+        for file in group.duplicates() {
+            set_color(&mut stdout, Some(Color::Rgb(240, 0, 0)));
+            writeln!(&mut stdout, "  -{}: {}", action_verb(&action), file.path.display()).expect("");
 
-                freed_space += fsize;
-                freed_files += 1;
+            if args.delete_files {
+                // actually apply action to file
+                let mut applied = &action;
+                let mut result = action.apply(group.keep(), file);
 
-                set_color(&mut stdout, Some(Color::Rgb(240, 0, 0)));
-                writeln!(&mut stdout, "  -deleting: {}", file.display()).expect("");
+                if let (Err(Error::Unsupported { .. }), Some(fallback)) = (&result, &args.fallback) {
+                    set_color(&mut stdout, Some(Color::Rgb(240, 0, 0)));
+                    writeln!(&mut stdout, "  -{} instead: {}", action_verb(fallback), file.path.display()).expect("");
+                    applied = fallback;
+                    result = fallback.apply(group.keep(), file);
+                }
 
-                if args.delete_files {
-                    // actually delete file
-                    remove_file(file)?;
+                match result {
+                    Ok(destination) => {
+                        freed_space += group.size;
+                        freed_files += 1;
+
+                        if let Some(j) = &mut journal {
+                            let entry = JournalEntry {
+                                timestamp: SystemTime::now(),
+                                action: applied.name().to_string(),
+                                size: group.size,
+                                hash: report.hash,
+                                checksum: group.checksum.clone(),
+                                kept: group.keep().path.clone(),
+                                path: file.path.clone(),
+                                destination,
+                            };
+
+                            if let Err(e) = j.write(&entry) {
+                                // Don't continue without a record of what was done
+                                writeln!(&mut stderr, "ERROR: {}", e).expect("");
+                                exit(1);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        // Files changed after scanning are always skipped
+                        if !args.skip_errors && !matches!(e, Error::Changed { .. }) {
+                            writeln!(&mut stderr, "ERROR: {}", e).expect("");
+                            exit(1);
+                        }
+
+                        errors.push(e.to_string());
+                    }
                 }
+            } else {
+                // Dry run, count what would be removed
+                freed_space += group.size;
+                freed_files += 1;
             }
         }
 
@@ -427,14 +720,81 @@ fn main() -> Result<(), io::Error> {
 
     writeln!(
         &mut stdout,
-        "(6 / 6) Removed {} files totaling {}",
+        "({} / {}) Removed {} files totaling {}",
+        steps,
+        steps,
         freed_files,
         convert_to_human(freed_space)
     ).expect("");
 
+    print_errors(&mut stderr, &errors);
+
     Ok(())
 }
 
+// Restore files listed in journal, newest first, returns exit code
+fn restore(path: &Path, stdout: &mut StandardStream, stderr: &mut StandardStream) -> i32 {
+    let entries = match Journal::read(path) {
+        Ok(r) => r,
+        Err(e) => {
+            writeln!(stderr, "ERROR: {}", e).expect("");
+            return 1;
+        }
+    };
+
+    let mut errors: Vec<String> = Vec::new();
+
+    for entry in entries.iter().rev() {
+        match entry.restore() {
+            Ok(RestoreOutcome::Restored) => {
+                set_color(stdout, Some(Color::Rgb(0, 240, 0)));
+                writeln!(stdout, "  +restored: {}", entry.path.display()).expect("");
+            }
+            Ok(RestoreOutcome::Present) => {
+                set_color(stdout, Some(Color::Rgb(160, 160, 160)));
+                writeln!(stdout, "  =already present: {}", entry.path.display()).expect("");
+            }
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+
+    print_errors(stderr, &errors);
+
+    if errors.is_empty() { 0 } else { 1 }
+}
+
+fn action_verb(action: &Action) -> &'static str {
+    match action {
+        Action::Delete => "deleting",
+        Action::HardLink => "hard linking",
+        Action::Reflink => "reflinking",
+        Action::Symlink | Action::RelativeSymlink => "symlinking",
+        Action::Move { .. } => "moving",
+        Action::Trash => "trashing",
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut c = s.chars();
+    match c.next() {
+        Some(f) => f.to_uppercase().chain(c).collect(),
+        None => String::new(),
+    }
+}
+
+// Summary of skipped files
+fn print_errors(target: &mut StandardStream, errors: &[String]) {
+    if errors.is_empty() {
+        return;
+    }
+
+    writeln!(target, "{} file(s) skipped because of errors:", errors.len()).expect("");
+
+    for e in errors {
+        writeln!(target, "  {}", e).expect("");
+    }
+}
+
 fn set_color(target: &mut StandardStream, color: Option<Color>) {
     target
         .set_color(ColorSpec::new().set_fg(color))
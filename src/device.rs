@@ -0,0 +1,167 @@
+// Block device information used for scheduling reads
+use std::collections::HashMap;
+use std::fmt;
+
+// How many files are read at the same time from one device, 0 means no limit besides the number of threads
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceReaders {
+    // Spinning hard drives, concurrent reads cause seeking
+    pub rotational: usize,
+    // SSDs, network and virtual file systems, and devices whose type is unknown
+    pub other: usize,
+}
+
+impl Default for DeviceReaders {
+    fn default() -> Self {
+        DeviceReaders {
+            rotational: 1,
+            other: 0,
+        }
+    }
+}
+
+impl DeviceReaders {
+    // Reader limit for device, 0 means no limit
+    pub(crate) fn limit(&self, rotational: Option<bool>) -> usize {
+        match rotational {
+            Some(true) => self.rotational,
+            _ => self.other,
+        }
+    }
+}
+
+impl fmt::Display for DeviceReaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = |n: usize| match n {
+            0 => "no limit".to_string(),
+            n => n.to_string(),
+        };
+
+        write!(f, "rotational: {}, other: {}", limit(self.rotational), limit(self.other))
+    }
+}
+
+// Disks of devices and their rotational flags, looked up once per device
+#[derive(Default)]
+pub(crate) struct Devices {
+    disks: HashMap<u64, (u64, Option<bool>)>,
+}
+
+impl Devices {
+    // Disk under device (st_dev) and whether it's rotational
+    pub(crate) fn disk(&mut self, dev: u64) -> (u64, Option<bool>) {
+        *self.disks.entry(dev).or_insert_with(|| {
+            let disk = disk(dev);
+            (disk, rotational(disk))
+        })
+    }
+
+    pub(crate) fn rotational(&mut self, dev: u64) -> Option<bool> {
+        self.disk(dev).1
+    }
+
+    // Reader queue of device for pool::run: files on partitions of the same disk share a queue
+    pub(crate) fn queue(&mut self, dev: u64, readers: &DeviceReaders) -> (u64, usize) {
+        let (disk, rotational) = self.disk(dev);
+        (disk, readers.limit(rotational))
+    }
+}
+
+// Whole disk holding the file system of device (st_dev).
+// Partitions resolve to their disk, device mapper volumes (LVM, dm-crypt) on a single device to that device.
+// File systems with anonymous device numbers (btrfs subvolumes) resolve through the device they are mounted from,
+// a btrfs file system spanning several devices is counted as its mounted device only.
+// Devices which aren't backed by a block device (tmpfs, network file systems) are returned as is.
+#[cfg(target_os = "linux")]
+pub(crate) fn disk(dev: u64) -> u64 {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let mut dev = dev;
+
+    if libc::major(dev) == 0 {
+        let mounts = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+
+        match mount_source(&mounts, dev).and_then(|p| std::fs::metadata(p).ok()) {
+            Some(m) if m.file_type().is_block_device() => dev = m.rdev(),
+            _ => return dev,
+        }
+    }
+
+    // Stacked devices are only a few levels deep, the limit guards against loops
+    for _ in 0..8 {
+        let dir = sysfs_dir(dev);
+
+        let parent = if dir.join("partition").exists() {
+            dir.canonicalize().ok().map(|d| d.join("../dev"))
+        } else {
+            let slaves: Vec<std::fs::DirEntry> = std::fs::read_dir(dir.join("slaves"))
+                .map(|it| it.filter_map(|e| e.ok()).collect())
+                .unwrap_or_default();
+
+            match slaves.as_slice() {
+                [slave] => Some(slave.path().join("dev")),
+                _ => None,
+            }
+        };
+
+        match parent.and_then(|p| read_dev(&p)) {
+            Some(parent) => dev = parent,
+            None => break,
+        }
+    }
+
+    dev
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn disk(dev: u64) -> u64 {
+    dev
+}
+
+#[cfg(target_os = "linux")]
+fn sysfs_dir(dev: u64) -> std::path::PathBuf {
+    std::path::PathBuf::from(format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev)))
+}
+
+// Device number from a sysfs dev file ("MAJOR:MINOR")
+#[cfg(target_os = "linux")]
+fn read_dev(path: &std::path::Path) -> Option<u64> {
+    let s = std::fs::read_to_string(path).ok()?;
+    let (major, minor) = s.trim().split_once(':')?;
+    Some(libc::makedev(major.parse().ok()?, minor.parse().ok()?))
+}
+
+// Source of the mount with given device number in mountinfo (/proc/self/mountinfo)
+#[cfg(target_os = "linux")]
+pub(crate) fn mount_source(mounts: &str, dev: u64) -> Option<std::path::PathBuf> {
+    let id = format!("{}:{}", libc::major(dev), libc::minor(dev));
+
+    mounts.lines().find_map(|line| {
+        // ID PARENT MAJOR:MINOR ROOT MOUNTPOINT OPTIONS [OPTIONAL..] - TYPE SOURCE SUPEROPTIONS
+        let fields: Vec<&str> = line.split(' ').collect();
+
+        if fields.get(2) != Some(&id.as_str()) {
+            return None;
+        }
+
+        let sep = fields.iter().skip(6).position(|&f| f == "-")? + 6;
+        fields.get(sep + 2).map(std::path::PathBuf::from)
+    })
+}
+
+// Is disk (see disk()) a spinning disk, None if it isn't a block device or its type is unknown
+#[cfg(target_os = "linux")]
+pub(crate) fn rotational(disk: u64) -> Option<bool> {
+    let flag = std::fs::read_to_string(sysfs_dir(disk).join("queue/rotational")).ok()?;
+
+    match flag.trim() {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn rotational(_disk: u64) -> Option<bool> {
+    None
+}
@@ -0,0 +1,237 @@
+use std::{fmt, io};
+use std::path::{Path, PathBuf};
+
+use crate::{Action, HashAlgorithm, Stage};
+
+// Errors returned by the library
+#[derive(Debug)]
+pub enum Error {
+    // Minimum count of files considered duplicate must be 2 or more
+    InvalidCount(u64),
+    // Partial hashing stages need a scan size of at least 1 byte
+    ZeroScanSize,
+    // Full hashing needs a cryptographic hash algorithm
+    WeakHash(HashAlgorithm),
+    // File returned no data although its size says otherwise (truncated while scanning?)
+    EmptyRead {
+        path: PathBuf,
+        stage: Stage,
+    },
+    // I/O error on a file or directory
+    Io {
+        path: PathBuf,
+        stage: Stage,
+        source: io::Error,
+    },
+    // Action failed on a duplicate file
+    Action {
+        path: PathBuf,
+        action: Action,
+        source: io::Error,
+    },
+    // File system doesn't support the action
+    Unsupported {
+        path: PathBuf,
+        action: Action,
+    },
+    // Duplicate is not on the same file system as the kept file
+    CrossDevice {
+        path: PathBuf,
+        keep: PathBuf,
+    },
+    // Duplicate is the kept file itself (same device and i-node, found through another path)
+    SameFile {
+        path: PathBuf,
+        keep: PathBuf,
+    },
+    // File changed after it was scanned
+    Changed {
+        path: PathBuf,
+        reason: String,
+    },
+    // Duplicate differs from the kept file although checksums match
+    // (hash collision or file modified after hashing)
+    ContentMismatch {
+        path: PathBuf,
+        keep: PathBuf,
+    },
+    // Reading or writing journal failed
+    Journal {
+        path: PathBuf,
+        source: io::Error,
+    },
+    // Journal has a malformed line
+    InvalidJournal {
+        path: PathBuf,
+        line: usize,
+    },
+    // Restoring a file from journal failed
+    Restore {
+        path: PathBuf,
+        source: io::Error,
+    },
+    // File doesn't have the checksum recorded in journal
+    ChecksumMismatch {
+        path: PathBuf,
+    },
+}
+
+impl Error {
+    pub(crate) fn io(path: &Path, stage: Stage, source: io::Error) -> Self {
+        Error::Io {
+            path: path.to_path_buf(),
+            stage,
+            source,
+        }
+    }
+
+    pub(crate) fn journal(path: &Path, source: io::Error) -> Self {
+        Error::Journal {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub(crate) fn restore(path: &Path, source: io::Error) -> Self {
+        Error::Restore {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    // Convert directory walking error, root is used if the error has no path
+    pub(crate) fn walk(root: &Path, e: walkdir::Error) -> Self {
+        let path = e.path().unwrap_or(root).to_path_buf();
+        let source = match e.into_io_error() {
+            Some(r) => r,
+            None => io::Error::other("file system loop found"),
+        };
+
+        Error::Io {
+            path,
+            stage: Stage::Size,
+            source,
+        }
+    }
+
+    // Offending file or directory, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::EmptyRead { path, .. }
+            | Error::Io { path, .. }
+            | Error::Action { path, .. }
+            | Error::Unsupported { path, .. }
+            | Error::CrossDevice { path, .. }
+            | Error::SameFile { path, .. }
+            | Error::ContentMismatch { path, .. }
+            | Error::Changed { path, .. }
+            | Error::Journal { path, .. }
+            | Error::InvalidJournal { path, .. }
+            | Error::Restore { path, .. }
+            | Error::ChecksumMismatch { path } => Some(path),
+            _ => None,
+        }
+    }
+
+    // Pipeline stage where the error happened, if any
+    pub fn stage(&self) -> Option<Stage> {
+        match self {
+            Error::EmptyRead { stage, .. } | Error::Io { stage, .. } => Some(*stage),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidCount(c) => write!(f, "count must be 2 or more, got {}", c),
+            Error::ZeroScanSize => write!(f, "scan size must be 1 or more"),
+            Error::WeakHash(h) => write!(f, "{} is not a cryptographic hash, it can only be used for partial hashing", h),
+            Error::EmptyRead { path, stage } => {
+                write!(f, "{}: {}: no data read", stage, path.display())
+            }
+            Error::Io { path, stage, source } => {
+                write!(f, "{}: {}: {}", stage, path.display(), source)
+            }
+            Error::Action { path, action, source } => {
+                write!(f, "{}: {}: {}", action, path.display(), source)
+            }
+            Error::Unsupported { path, action } => {
+                write!(f, "{}: {}: not supported by the file system", action, path.display())
+            }
+            Error::CrossDevice { path, keep } => {
+                write!(f, "{}: not on the same file system as {}", path.display(), keep.display())
+            }
+            Error::SameFile { path, keep } => {
+                write!(f, "{}: same file as {}", path.display(), keep.display())
+            }
+            Error::Changed { path, reason } => {
+                write!(f, "{}: changed after scanning: {}", path.display(), reason)
+            }
+            Error::ContentMismatch { path, keep } => {
+                write!(
+                    f,
+                    "{}: contents differ from {} (hash collision or modified after hashing?)",
+                    path.display(),
+                    keep.display()
+                )
+            }
+            Error::Journal { path, source } => {
+                write!(f, "journal: {}: {}", path.display(), source)
+            }
+            Error::InvalidJournal { path, line } => {
+                write!(f, "journal: {}: invalid line {}", path.display(), line)
+            }
+            Error::Restore { path, source } => {
+                write!(f, "restore: {}: {}", path.display(), source)
+            }
+            Error::ChecksumMismatch { path } => {
+                write!(f, "{}: checksum differs from journal", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. }
+            | Error::Action { source, .. }
+            | Error::Journal { source, .. }
+            | Error::Restore { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Collects per-file errors when they are skipped, otherwise passes them on
+pub(crate) struct ErrorLog {
+    skip: bool,
+    errors: Vec<Error>,
+}
+
+impl ErrorLog {
+    pub(crate) fn new(skip: bool) -> Self {
+        ErrorLog {
+            skip,
+            errors: Vec::new(),
+        }
+    }
+
+    // Record the error if errors are skipped, otherwise return it
+    pub(crate) fn handle(&mut self, e: Error) -> Result<()> {
+        if !self.skip {
+            return Err(e);
+        }
+
+        self.errors.push(e);
+        Ok(())
+    }
+
+    pub(crate) fn into_errors(self) -> Vec<Error> {
+        self.errors
+    }
+}
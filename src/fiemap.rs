@@ -0,0 +1,75 @@
+// Physical location of file data using the FIEMAP ioctl
+use std::io;
+use std::path::Path;
+
+// Position on disk of the byte at offset of file,
+// None if the file system doesn't map the data to a position (holes, inline data, ..)
+#[cfg(target_os = "linux")]
+pub(crate) fn physical_offset(path: &Path, offset: u64) -> io::Result<Option<u64>> {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: u32 = 0xC020660B;
+    // Extent data isn't stored on the block device or its location isn't known yet
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+    const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+
+    #[repr(C)]
+    struct FiemapExtent {
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_reserved64: [u64; 2],
+        fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+
+    // struct fiemap with room for one extent
+    #[repr(C)]
+    struct Fiemap {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fm_extents: [FiemapExtent; 1],
+    }
+
+    let f = File::open(path)?;
+
+    let mut m = Fiemap {
+        fm_start: offset,
+        fm_length: 1,
+        fm_flags: 0,
+        fm_mapped_extents: 0,
+        fm_extent_count: 1,
+        fm_reserved: 0,
+        fm_extents: [FiemapExtent {
+            fe_logical: 0,
+            fe_physical: 0,
+            fe_length: 0,
+            fe_reserved64: [0; 2],
+            fe_flags: 0,
+            fe_reserved: [0; 3],
+        }],
+    };
+
+    if unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_FIEMAP as libc::Ioctl, &mut m) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let e = &m.fm_extents[0];
+
+    if m.fm_mapped_extents == 0 || e.fe_flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DATA_INLINE) != 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(e.fe_physical + offset.saturating_sub(e.fe_logical)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn physical_offset(_path: &Path, _offset: u64) -> io::Result<Option<u64>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
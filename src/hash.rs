@@ -0,0 +1,130 @@
+// Checksum algorithms for hashing file contents
+use std::fmt;
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256, Sha512};
+
+// Incremental checksum calculation
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+    // Checksum of all data given so far
+    fn finish(self: Box<Self>) -> Vec<u8>;
+    // Copy of the current state, for continuing with different data
+    fn clone_box(&self) -> Box<dyn Hasher + Send>;
+}
+
+impl Hasher for Sha512 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.finalize().to_vec()
+    }
+
+    fn clone_box(&self) -> Box<dyn Hasher + Send> {
+        Box::new(self.clone())
+    }
+}
+
+impl Hasher for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.finalize().to_vec()
+    }
+
+    fn clone_box(&self) -> Box<dyn Hasher + Send> {
+        Box::new(self.clone())
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.finalize().as_bytes().to_vec()
+    }
+
+    fn clone_box(&self) -> Box<dyn Hasher + Send> {
+        Box::new(self.clone())
+    }
+}
+
+impl Hasher for xxhash_rust::xxh3::Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.digest128().to_be_bytes().to_vec()
+    }
+
+    fn clone_box(&self) -> Box<dyn Hasher + Send> {
+        Box::new(self.clone())
+    }
+}
+
+// Checksum algorithm
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha512,
+    Sha256,
+    Blake3,
+    // 128 bit xxHash3, fast but not cryptographic, only for partial hashing stages
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 4] = [
+        HashAlgorithm::Sha512,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Xxh3,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+
+    // Collisions can't be crafted, required for the full hashing stage
+    pub fn is_cryptographic(&self) -> bool {
+        *self != HashAlgorithm::Xxh3
+    }
+
+    pub fn hasher(&self) -> Box<dyn Hasher + Send> {
+        match self {
+            HashAlgorithm::Sha512 => Box::new(Sha512::new()),
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            HashAlgorithm::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match HashAlgorithm::ALL.iter().find(|a| a.name() == s) {
+            Some(a) => Ok(*a),
+            None => Err(format!("unknown hash algorithm: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,273 @@
+// Journal of actions taken on duplicate files, used for restoring them
+//
+// Tab separated text file, one action per line:
+// timestamp  action  size  hash algorithm  checksum  kept path  duplicate path  new location of duplicate (if moved)
+// Version 1 journals have no hash algorithm field, their checksums are SHA512.
+use std::ffi::OsStr;
+use std::fs::{copy, create_dir_all, read_link, symlink_metadata, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::io;
+
+use crate::action::{move_file, replace_with};
+use crate::{hash_full, trash, Action, Error, HashAlgorithm, Result};
+
+const HEADER: &str = "# samanlainen journal v2";
+
+// One action taken on a duplicate file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub timestamp: SystemTime,
+    // Name of the action, see Action::name
+    pub action: String,
+    // File size
+    pub size: u64,
+    // Checksum algorithm
+    pub hash: HashAlgorithm,
+    // Checksum of the kept file and the duplicate
+    pub checksum: String,
+    // Kept file
+    pub kept: PathBuf,
+    // Duplicate file which was removed or replaced
+    pub path: PathBuf,
+    // New location of the duplicate if it was moved
+    pub destination: Option<PathBuf>,
+}
+
+// What restoring a journal entry did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    // Duplicate was moved back or recreated
+    Restored,
+    // Path already holds an independent file (reflinked duplicate, restored earlier or replaced), nothing was done
+    Present,
+}
+
+// Journal file opened for appending entries
+pub struct Journal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl Journal {
+    // Open journal for appending, it's created if it doesn't exist
+    pub fn create<P: Into<PathBuf>>(path: P) -> Result<Journal> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| Error::journal(&path, e))?;
+
+        let empty = file.metadata().map_err(|e| Error::journal(&path, e))?.len() == 0;
+        let mut journal = Journal {
+            path,
+            writer: BufWriter::new(file),
+        };
+
+        if empty {
+            journal.write_line(HEADER)?;
+        }
+
+        Ok(journal)
+    }
+
+    // Append entry, it's flushed to disk right away
+    pub fn write(&mut self, entry: &JournalEntry) -> Result<()> {
+        let timestamp = entry.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let fields = [
+            timestamp.to_string(),
+            escape(OsStr::new(&entry.action)),
+            entry.size.to_string(),
+            entry.hash.to_string(),
+            escape(OsStr::new(&entry.checksum)),
+            escape(entry.kept.as_os_str()),
+            escape(entry.path.as_os_str()),
+            entry.destination.as_ref().map(|p| escape(p.as_os_str())).unwrap_or_default(),
+        ];
+
+        self.write_line(&fields.join("\t"))
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        writeln!(self.writer, "{}", line)
+            .and_then(|_| self.writer.flush())
+            .and_then(|_| self.writer.get_ref().sync_data())
+            .map_err(|e| Error::journal(&self.path, e))
+    }
+
+    // Read all entries of a journal file
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<JournalEntry>> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| Error::journal(path, e))?;
+        let mut entries: Vec<JournalEntry> = Vec::new();
+
+        for (i, line) in BufReader::new(file).split(b'\n').enumerate() {
+            let line = line.map_err(|e| Error::journal(path, e))?;
+
+            if line.is_empty() || line.starts_with(b"#") {
+                continue;
+            }
+
+            match parse_line(&line) {
+                Some(e) => entries.push(e),
+                None => {
+                    return Err(Error::InvalidJournal {
+                        path: path.to_path_buf(),
+                        line: i + 1,
+                    });
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+impl JournalEntry {
+    // Recreate the duplicate: move it back from its new location,
+    // or copy the kept file in place of the removed or replaced duplicate
+    pub fn restore(&self) -> Result<RestoreOutcome> {
+        let path = &self.path;
+
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).map_err(|e| Error::restore(parent, e))?;
+        }
+
+        if let Some(destination) = &self.destination {
+            if symlink_metadata(path).is_ok() {
+                return Err(Error::restore(path, io::ErrorKind::AlreadyExists.into()));
+            }
+
+            move_file(destination, path).map_err(|e| Error::restore(path, e))?;
+
+            if self.action == Action::Trash.name() {
+                trash::remove_info(destination).map_err(|e| Error::restore(destination, e))?;
+            }
+
+            return Ok(RestoreOutcome::Restored);
+        }
+
+        match symlink_metadata(path) {
+            Ok(m) if m.file_type().is_symlink() => {
+                // Replaced with a symbolic link, restore only if it still points to the kept file
+                let target = read_link(path).map_err(|e| Error::restore(path, e))?;
+                let target = path.parent().unwrap_or(Path::new("")).join(target);
+
+                if !same_file(&target, &self.kept) {
+                    return Err(Error::restore(path, io::ErrorKind::AlreadyExists.into()));
+                }
+            }
+            Ok(_) if same_file(path, &self.kept) => {
+                // Replaced with a hard link
+            }
+            Ok(_) => {
+                // Still an independent file (reflink) or replaced by something else, leave it alone
+                return Ok(RestoreOutcome::Present);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // Deleted
+            }
+            Err(e) => return Err(Error::restore(path, e)),
+        }
+
+        if hash_full(&self.kept, self.hash)? != self.checksum {
+            return Err(Error::ChecksumMismatch {
+                path: self.kept.to_path_buf(),
+            });
+        }
+
+        replace_with(path, |tmp| copy(&self.kept, tmp).map(|_| ()))
+            .map_err(|e| Error::restore(path, e))?;
+
+        Ok(RestoreOutcome::Restored)
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+fn parse_line(line: &[u8]) -> Option<JournalEntry> {
+    let mut fields: Vec<&[u8]> = line.split(|&b| b == b'\t').collect();
+
+    match fields.len() {
+        // Version 1
+        7 => fields.insert(3, HashAlgorithm::Sha512.name().as_bytes()),
+        8 => {}
+        _ => return None,
+    }
+
+    let text = |b: &[u8]| String::from_utf8(unescape(b)?).ok();
+    let path = |b: &[u8]| unescape(b).map(|b| PathBuf::from(OsStr::from_bytes(&b)));
+
+    Some(JournalEntry {
+        timestamp: UNIX_EPOCH + Duration::from_secs(text(fields[0])?.parse().ok()?),
+        action: text(fields[1])?,
+        size: text(fields[2])?.parse().ok()?,
+        hash: text(fields[3])?.parse().ok()?,
+        checksum: text(fields[4])?,
+        kept: path(fields[5])?,
+        path: path(fields[6])?,
+        destination: if fields[7].is_empty() { None } else { Some(path(fields[7])?) },
+    })
+}
+
+// Escape backslash, tab and line feed, and bytes which are not valid UTF-8
+fn escape(s: &OsStr) -> String {
+    let mut r = String::new();
+
+    for chunk in s.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => r.push_str("\\\\"),
+                '\t' => r.push_str("\\t"),
+                '\n' => r.push_str("\\n"),
+                '\r' => r.push_str("\\r"),
+                c => r.push(c),
+            }
+        }
+
+        for b in chunk.invalid() {
+            r.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+
+    r
+}
+
+fn unescape(s: &[u8]) -> Option<Vec<u8>> {
+    let mut r: Vec<u8> = Vec::with_capacity(s.len());
+    let mut i = 0;
+
+    while i < s.len() {
+        if s[i] != b'\\' {
+            r.push(s[i]);
+            i += 1;
+            continue;
+        }
+
+        match s.get(i + 1)? {
+            b'\\' => r.push(b'\\'),
+            b't' => r.push(b'\t'),
+            b'n' => r.push(b'\n'),
+            b'r' => r.push(b'\r'),
+            b'x' => {
+                let hex = std::str::from_utf8(s.get(i + 2..i + 4)?).ok()?;
+                r.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 2;
+            }
+            _ => return None,
+        }
+
+        i += 2;
+    }
+
+    Some(r)
+}
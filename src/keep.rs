@@ -0,0 +1,120 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::FileEntry;
+
+// Rule for choosing which file of a duplicate group is kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeepRule {
+    // File under the path listed first
+    Priority,
+    // Oldest modification time
+    Oldest,
+    // Newest modification time
+    Newest,
+    // Shortest full path
+    ShortestPath,
+    // Fewest directories below the scanned path
+    Shallowest,
+    // File name first in lexicographic order
+    Name,
+    // Most hard links
+    MostLinks,
+}
+
+impl KeepRule {
+    pub const ALL: [KeepRule; 7] = [
+        KeepRule::Priority,
+        KeepRule::Oldest,
+        KeepRule::Newest,
+        KeepRule::ShortestPath,
+        KeepRule::Shallowest,
+        KeepRule::Name,
+        KeepRule::MostLinks,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeepRule::Priority => "priority",
+            KeepRule::Oldest => "oldest",
+            KeepRule::Newest => "newest",
+            KeepRule::ShortestPath => "shortest-path",
+            KeepRule::Shallowest => "shallowest",
+            KeepRule::Name => "name",
+            KeepRule::MostLinks => "most-links",
+        }
+    }
+
+    // Less means a is preferred over b
+    fn compare(&self, a: &FileEntry, b: &FileEntry) -> Ordering {
+        match self {
+            KeepRule::Priority => a.root.cmp(&b.root),
+            KeepRule::Oldest => a.modified.cmp(&b.modified),
+            KeepRule::Newest => b.modified.cmp(&a.modified),
+            KeepRule::ShortestPath => a.path.as_os_str().len().cmp(&b.path.as_os_str().len()),
+            KeepRule::Shallowest => a.depth.cmp(&b.depth),
+            KeepRule::Name => a.path.file_name().cmp(&b.path.file_name()),
+            KeepRule::MostLinks => b.links.cmp(&a.links),
+        }
+    }
+}
+
+impl fmt::Display for KeepRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for KeepRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match KeepRule::ALL.iter().find(|r| r.name() == s) {
+            Some(r) => Ok(*r),
+            None => Err(format!("unknown keep rule: {}", s)),
+        }
+    }
+}
+
+// Chain of keep rules, later rules break ties of the earlier ones
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeepPolicy {
+    rules: Vec<KeepRule>,
+}
+
+impl Default for KeepPolicy {
+    // Files under higher priority paths first, then oldest first
+    fn default() -> Self {
+        KeepPolicy::new(vec![KeepRule::Priority, KeepRule::Oldest])
+    }
+}
+
+impl KeepPolicy {
+    pub fn new(rules: Vec<KeepRule>) -> Self {
+        KeepPolicy { rules }
+    }
+
+    // Add tie-breaker rule
+    pub fn then(mut self, rule: KeepRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn rules(&self) -> &[KeepRule] {
+        &self.rules
+    }
+
+    // Less means a is preferred over b, full path is the final tie-breaker
+    pub fn compare(&self, a: &FileEntry, b: &FileEntry) -> Ordering {
+        self.rules
+            .iter()
+            .fold(Ordering::Equal, |o, r| o.then_with(|| r.compare(a, b)))
+            .then_with(|| a.path.cmp(&b.path))
+    }
+
+    // Order files so that the one to keep is first
+    pub fn sort(&self, files: &mut [FileEntry]) {
+        files.sort_by(|a, b| self.compare(a, b));
+    }
+}
@@ -0,0 +1,128 @@
+// Stage functions of the library before DuplicateFinder, kept for existing callers.
+// They run the same stages as DuplicateFinder and convert between file paths and the file table.
+use std::collections::HashMap;
+use std::fs::metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::ErrorLog;
+use crate::table::{Candidates, FileTable};
+use crate::{Error, ScanOptions, ScanType, SortOrder, StageIo};
+
+fn to_io(e: Error) -> io::Error {
+    io::Error::other(e)
+}
+
+// Add files grouped by size to a new file table
+fn table_of(l: HashMap<u64, Vec<PathBuf>>) -> io::Result<(FileTable, Candidates)> {
+    let mut table = FileTable::default();
+    let mut files: Candidates = HashMap::new();
+
+    for (fsize, paths) in l {
+        for path in paths {
+            let m = metadata(&path)?;
+            let dir = table.add_dir(path.parent().unwrap_or(Path::new("")));
+            let name = path.file_name().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+            files.entry(fsize).or_default().push(table.add_file(dir, name, 0, 0, &m));
+        }
+    }
+
+    Ok((table, files))
+}
+
+fn paths_of(table: &FileTable, l: Candidates) -> HashMap<u64, Vec<PathBuf>> {
+    l.into_iter()
+        .map(|(fsize, files)| (fsize, files.into_iter().map(|i| table.path(i)).collect()))
+        .collect()
+}
+
+// Generate stats from list of files
+#[deprecated(note = "use DuplicateFinder, stats of each stage are in DuplicateReport::stages")]
+pub fn generate_stats(l: HashMap<u64, Vec<PathBuf>>) -> (u64, u64) {
+    let file_count = l.values().map(|files| files.len() as u64).sum();
+    let total_size = l.iter().map(|(fsize, files)| fsize * files.len() as u64).sum();
+
+    (file_count, total_size)
+}
+
+// Find possible duplicates based on last or first bytes of files.
+// Files up to twice the scan size are passed on without hashing, as in DuplicateFinder.
+#[deprecated(note = "use DuplicateFinder with ScanOptions::stages, files up to twice the scan size are now passed on without hashing")]
+pub fn eliminate_first_or_last_bytes_hash(
+    l: HashMap<u64, Vec<PathBuf>>, // List of files
+    t: ScanType, // Scan first or last bytes of file
+    scansize: u64, // how many bytes to scan
+    min_count: u64, // minimal count considered as duplicate (2 or more)
+) -> io::Result<HashMap<u64, Vec<PathBuf>>> {
+    if min_count < 2 {
+        return Err(to_io(Error::InvalidCount(min_count)));
+    }
+
+    let o = ScanOptions {
+        scansize,
+        count: min_count,
+        stages: vec![t.into()],
+        ..ScanOptions::default()
+    };
+
+    let (table, files) = table_of(l)?;
+    let files = crate::eliminate_partial(
+        &table,
+        files,
+        0,
+        &mut HashMap::new(),
+        &o,
+        &mut StageIo::default(),
+        &mut ErrorLog::new(false),
+        &mut |_| {},
+    ).map_err(to_io)?;
+
+    Ok(paths_of(&table, files))
+}
+
+// Find initial candidates from given path(s)
+#[deprecated(note = "use DuplicateFinder")]
+pub fn find_candidate_files(
+    paths: Vec<PathBuf>, // file path(s) to scan for files
+    minimum_size: u64, // file size must be at least this
+    maximum_size: u64, // file size cannot be larger than this
+    count: u64, // there must be at least this many files with same file size to be considered a duplicate (must be 2 or more)
+) -> io::Result<HashMap<u64, Vec<PathBuf>>> {
+    if count < 2 {
+        return Err(to_io(Error::InvalidCount(count)));
+    }
+
+    let (table, files) = crate::collect_candidates(
+        &paths,
+        minimum_size,
+        maximum_size,
+        count,
+        SortOrder::Inode,
+        &mut ErrorLog::new(false),
+        &mut |_| {},
+    ).map_err(to_io)?;
+
+    Ok(paths_of(&table, files))
+}
+
+// Hashes files fully and returns file list and checksum as the key
+#[deprecated(note = "use DuplicateFinder")]
+pub fn find_final_candidates(
+    l: Vec<PathBuf>, // List of files
+) -> io::Result<HashMap<String, Vec<PathBuf>>> {
+    let mut sizes: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+
+    for path in l {
+        sizes.entry(metadata(&path)?.len()).or_default().push(path);
+    }
+
+    let (table, files) = table_of(sizes)?;
+    let hashes = crate::hash_candidates(&table, files, &ScanOptions::default(), &mut StageIo::default(), &mut ErrorLog::new(false), &mut |_| {})
+        .map_err(to_io)?;
+
+    Ok(hashes
+        .into_iter()
+        .map(|((_, checksum), files)| (checksum, files.into_iter().map(|i| table.path(i)).collect()))
+        .collect())
+}
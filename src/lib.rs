@@ -1,15 +1,588 @@
-use std::{io, iter};
-use std::collections::HashMap;
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
 use std::fs::File;
+use std::os::unix::fs::MetadataExt;
 use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::fmt::{Write};
 
-use sha2::{Digest, Sha512};
-use walkdir::{DirEntryExt, WalkDir};
+use walkdir::{DirEntry, DirEntryExt, WalkDir};
+
+pub use action::Action;
+pub use device::DeviceReaders;
+use device::Devices;
+pub use error::{Error, Result};
+pub use hash::{HashAlgorithm, Hasher};
+use error::ErrorLog;
+pub use journal::{Journal, JournalEntry, RestoreOutcome};
+pub use keep::{KeepPolicy, KeepRule};
+#[allow(deprecated)]
+pub use legacy::{eliminate_first_or_last_bytes_hash, find_candidate_files, find_final_candidates, generate_stats};
+use table::{Candidates, Checksums, FileIndex, FileTable};
+
+mod action;
+mod device;
+mod error;
+mod fiemap;
+mod hash;
+mod journal;
+mod keep;
+mod legacy;
+mod pool;
+mod reflink;
+mod table;
+mod trash;
+
+// Options for a duplicate file scan
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    // Path(s) to scan for files, listed in priority order
+    pub paths: Vec<PathBuf>,
+    // File size must be at least this
+    pub minimum_size: u64,
+    // File size cannot be larger than this
+    pub maximum_size: u64,
+    // There must be at least this many files with same contents to be considered a duplicate (2 or more)
+    pub count: u64,
+    // How many bytes to scan in partial hashing stages which don't set their own size
+    pub scansize: u64,
+    // Derive the scan size of stages which don't set their own size from the file size and
+    // number of files of each size group instead of using scansize, see adaptive_scansize
+    pub adaptive_scansize: bool,
+    // Partial hashing stages run between size grouping and full hashing, in this order
+    pub stages: Vec<PartialStage>,
+    // Skip files and directories which can't be read and record the errors instead of aborting
+    pub skip_errors: bool,
+    // How the file to keep is chosen from each duplicate group
+    pub keep: KeepPolicy,
+    // Order of directory entries while walking directories
+    pub sort_order: SortOrder,
+    // Number of threads hashing files, 0 uses one thread per CPU core
+    pub threads: usize,
+    // How many files are read at the same time from one device
+    pub readers: DeviceReaders,
+    // Read files on rotational hard drives in the order of their data on disk, instead of by file size
+    pub physical_order: bool,
+    // Checksum algorithm of the full hashing stage, must be cryptographic
+    pub hash: HashAlgorithm,
+    // Checksum algorithm of partial hashing stages
+    pub partial_hash: HashAlgorithm,
+    // Size groups of at most this many files are compared byte by byte in lockstep
+    // instead of being hashed fully, 0 hashes every group. Limited to MAX_COMPARE
+    // as every file of a group is open at the same time.
+    pub compare: usize,
+}
+
+// Largest size group compared in lockstep, see ScanOptions::compare
+pub const MAX_COMPARE: usize = 64;
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            paths: Vec::new(),
+            minimum_size: 1,
+            maximum_size: u64::MAX,
+            count: 2,
+            scansize: 1048576,
+            adaptive_scansize: false,
+            stages: vec![ScanType::Last.into(), ScanType::First.into()],
+            skip_errors: false,
+            keep: KeepPolicy::default(),
+            sort_order: SortOrder::Inode,
+            threads: 0,
+            readers: DeviceReaders::default(),
+            physical_order: false,
+            hash: HashAlgorithm::Sha512,
+            partial_hash: HashAlgorithm::Sha512,
+            compare: 0,
+        }
+    }
+}
+
+impl ScanOptions {
+    // Bytes to scan per block by partial stage p from files of given size, in a size group of file_count files
+    pub fn partial_scansize(&self, p: &PartialStage, size: u64, file_count: u64) -> u64 {
+        match p.size {
+            Some(s) => s,
+            None if self.adaptive_scansize => adaptive_scansize(size, file_count),
+            None => self.scansize,
+        }
+    }
+}
+
+// Pipeline stage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    // Walk directories and group files by file size
+    Size,
+    // Hash first, last or sampled bytes of files
+    Partial(ScanType),
+    // Hash the entire files
+    Full,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Size => write!(f, "size"),
+            Stage::Partial(ScanType::First) => write!(f, "first bytes"),
+            Stage::Partial(ScanType::Last) => write!(f, "last bytes"),
+            Stage::Partial(ScanType::Middle) => write!(f, "middle bytes"),
+            Stage::Partial(ScanType::Sampled(n)) => write!(f, "{} sampled blocks", n),
+            Stage::Full => write!(f, "full hash"),
+        }
+    }
+}
+
+// Candidates left after a stage
+#[derive(Clone, Debug)]
+pub struct StageStats {
+    pub stage: Stage,
+    pub file_count: u64,
+    pub total_size: u64,
+    // Bytes read from files by the stage
+    pub bytes_read: u64,
+    // Bytes the stage didn't need to read: partial reads skipped for small files,
+    // rest of the files after lockstep comparison found a difference
+    pub bytes_saved: u64,
+    // Candidates dropped by the stage
+    pub eliminated: u64,
+}
+
+// Bytes read and saved by a stage, see StageStats
+#[derive(Default)]
+struct StageIo {
+    read: u64,
+    saved: u64,
+}
+
+// Progress of a running scan
+#[derive(Debug)]
+pub enum Progress<'a> {
+    // Stage is starting with the candidates left by the previous stage
+    StageStarted(Stage),
+    // Stage has finished
+    StageFinished(&'a StageStats),
+    // Full hashing of one file size group is starting
+    HashingGroup {
+        size: u64,
+        file_count: u64,
+    },
+    // Partial hashing of one file size group is starting with given scan size per block
+    ScanningGroup {
+        scan: ScanType,
+        size: u64,
+        file_count: u64,
+        scansize: u64,
+    },
+    // Lockstep comparison of one file size group is starting, see ScanOptions::compare
+    ComparingGroup {
+        size: u64,
+        file_count: u64,
+    },
+    // File was not added as a candidate while walking directories
+    Skipped {
+        path: &'a Path,
+        reason: SkipReason,
+    },
+    // File was hashed in a partial or full hashing stage
+    Hashed {
+        stage: Stage,
+        path: &'a Path,
+        checksum: &'a str,
+    },
+    // File has no duplicates left after given stage and was dropped
+    Eliminated {
+        stage: Stage,
+        path: &'a Path,
+    },
+}
+
+// Why a file was not added as a candidate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    // Symbolic links are not followed
+    Symlink,
+    // Not a regular file (socket, device, ..)
+    NotFile,
+    // Zero sized file
+    Empty,
+    // Smaller than minimum size
+    TooSmall,
+    // Larger than maximum size
+    TooLarge,
+    // Same file (hard link) was already found
+    SameInode,
+    // Same directory was already scanned through another path (nested roots, bind mounts)
+    SameDirectory,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Symlink => write!(f, "symbolic link"),
+            SkipReason::NotFile => write!(f, "not a regular file"),
+            SkipReason::Empty => write!(f, "empty file"),
+            SkipReason::TooSmall => write!(f, "too small"),
+            SkipReason::TooLarge => write!(f, "too large"),
+            SkipReason::SameInode => write!(f, "same i-node already found"),
+            SkipReason::SameDirectory => write!(f, "directory already scanned"),
+        }
+    }
+}
+
+// Identity of a file: device and i-node number
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FileId {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl FileId {
+    pub fn from_metadata(m: &std::fs::Metadata) -> Self {
+        FileId {
+            dev: m.dev(),
+            ino: m.ino(),
+        }
+    }
+}
+
+// File found while scanning
+#[derive(Clone, Debug)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    // Identity and size when scanned, see FileEntry::check
+    pub id: FileId,
+    pub size: u64,
+    // Index of the scanned path this file was found under, lower index has higher priority
+    pub root: usize,
+    // Directory depth below the scanned path, files directly under it have depth 1
+    pub depth: usize,
+    // Last modification time
+    pub modified: SystemTime,
+    // Number of hard links
+    pub links: u64,
+}
+
+impl FileEntry {
+    // Check that the file at path is still the same file, unmodified since it was scanned
+    pub fn check(&self) -> Result<()> {
+        let m = std::fs::symlink_metadata(&self.path).map_err(|e| Error::Changed {
+            path: self.path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+
+        let reason = if !m.file_type().is_file() {
+            "not a regular file anymore"
+        } else if FileId::from_metadata(&m) != self.id {
+            "replaced by another file"
+        } else if m.len() != self.size {
+            "size changed"
+        } else if m.modified().unwrap_or(UNIX_EPOCH) != self.modified {
+            "modification time changed"
+        } else {
+            return Ok(());
+        };
+
+        Err(Error::Changed {
+            path: self.path.to_path_buf(),
+            reason: reason.to_string(),
+        })
+    }
+}
+
+// Files sharing the same contents
+#[derive(Clone, Debug)]
+pub struct DuplicateGroup {
+    // File size of each file
+    pub size: u64,
+    // Checksum of each file, see DuplicateReport::hash
+    pub checksum: String,
+    // Files in keep order (see ScanOptions::keep), the first one is kept
+    pub files: Vec<FileEntry>,
+}
+
+impl DuplicateGroup {
+    // File to keep
+    pub fn keep(&self) -> &FileEntry {
+        &self.files[0]
+    }
+
+    // Files to remove
+    pub fn duplicates(&self) -> &[FileEntry] {
+        &self.files[1..]
+    }
+
+    // Compare duplicate byte by byte against the kept file
+    pub fn verify(&self, duplicate: &FileEntry) -> Result<()> {
+        let keep = self.keep();
+
+        match action::same_contents(&keep.path, &duplicate.path) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(Error::ContentMismatch {
+                path: duplicate.path.to_path_buf(),
+                keep: keep.path.to_path_buf(),
+            }),
+            Err(e) => Err(Error::io(&duplicate.path, Stage::Full, e)),
+        }
+    }
+}
+
+// Result of a duplicate file scan
+#[derive(Debug, Default)]
+pub struct DuplicateReport {
+    // Checksum algorithm of duplicate groups and the full hashing stage
+    pub hash: HashAlgorithm,
+    // Checksum algorithm of partial hashing stages
+    pub partial_hash: HashAlgorithm,
+    // Duplicate groups, ordered by file size and checksum
+    pub groups: Vec<DuplicateGroup>,
+    // Candidates left after each stage that was run
+    pub stages: Vec<StageStats>,
+    // Skipped files and directories, see ScanOptions::skip_errors
+    pub errors: Vec<Error>,
+}
+
+// Builder for running a duplicate file scan
+#[derive(Clone, Debug, Default)]
+pub struct DuplicateFinder {
+    options: ScanOptions,
+}
+
+impl DuplicateFinder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: ScanOptions) -> Self {
+        DuplicateFinder { options }
+    }
+
+    pub fn options(&self) -> &ScanOptions {
+        &self.options
+    }
+
+    // Add path to scan, paths added first have higher priority
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.options.paths.push(path.into());
+        self
+    }
+
+    pub fn paths<I, P>(mut self, paths: I) -> Self
+        where I: IntoIterator<Item=P>, P: Into<PathBuf> {
+        self.options.paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn minimum_size(mut self, size: u64) -> Self {
+        self.options.minimum_size = size;
+        self
+    }
+
+    pub fn maximum_size(mut self, size: u64) -> Self {
+        self.options.maximum_size = size;
+        self
+    }
+
+    pub fn count(mut self, count: u64) -> Self {
+        self.options.count = count;
+        self
+    }
+
+    pub fn scansize(mut self, size: u64) -> Self {
+        self.options.scansize = size;
+        self
+    }
+
+    pub fn adaptive_scansize(mut self, adaptive: bool) -> Self {
+        self.options.adaptive_scansize = adaptive;
+        self
+    }
+
+    pub fn stages<I, S>(mut self, stages: I) -> Self
+        where I: IntoIterator<Item=S>, S: Into<PartialStage> {
+        self.options.stages = stages.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn skip_errors(mut self, skip: bool) -> Self {
+        self.options.skip_errors = skip;
+        self
+    }
+
+    pub fn keep(mut self, policy: KeepPolicy) -> Self {
+        self.options.keep = policy;
+        self
+    }
+
+    pub fn sort_order(mut self, order: SortOrder) -> Self {
+        self.options.sort_order = order;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    pub fn readers(mut self, readers: DeviceReaders) -> Self {
+        self.options.readers = readers;
+        self
+    }
+
+    pub fn physical_order(mut self, physical: bool) -> Self {
+        self.options.physical_order = physical;
+        self
+    }
+
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+        self.options.hash = hash;
+        self
+    }
+
+    pub fn partial_hash(mut self, hash: HashAlgorithm) -> Self {
+        self.options.partial_hash = hash;
+        self
+    }
+
+    pub fn compare(mut self, max_files: usize) -> Self {
+        self.options.compare = max_files;
+        self
+    }
+
+    pub fn run(&self) -> Result<DuplicateReport> {
+        self.run_with(|_| {})
+    }
+
+    // Run the scan, reporting progress to given callback
+    pub fn run_with<F: FnMut(&Progress)>(&self, mut progress: F) -> Result<DuplicateReport> {
+        let o = &self.options;
+
+        if o.count < 2 {
+            return Err(Error::InvalidCount(o.count));
+        }
+
+        if o.stages.iter().any(|p| p.size.or((!o.adaptive_scansize).then_some(o.scansize)) == Some(0)) {
+            return Err(Error::ZeroScanSize);
+        }
+
+        if !o.hash.is_cryptographic() {
+            return Err(Error::WeakHash(o.hash));
+        }
+
+        let mut report = DuplicateReport {
+            hash: o.hash,
+            partial_hash: o.partial_hash,
+            ..DuplicateReport::default()
+        };
+        let mut errors = ErrorLog::new(o.skip_errors);
+
+        self.scan(&mut report, &mut errors, &mut progress)?;
+
+        report.errors = errors.into_errors();
+        Ok(report)
+    }
+
+    fn scan<F: FnMut(&Progress)>(
+        &self,
+        report: &mut DuplicateReport,
+        errors: &mut ErrorLog,
+        progress: &mut F,
+    ) -> Result<()> {
+        let o = &self.options;
+
+        progress(&Progress::StageStarted(Stage::Size));
+        let (mut table, mut files) = collect_candidates(
+            &o.paths,
+            o.minimum_size,
+            o.maximum_size,
+            o.count,
+            o.sort_order,
+            errors,
+            progress,
+        )?;
+        finish_stage(report, Stage::Size, table.len() as u64, &files, StageIo::default(), progress);
+
+        // Forget files which can't have duplicates
+        table.retain(&mut files);
+
+        // Bytes read from each file of a size by partial stages
+        let mut covered: HashMap<u64, u64> = HashMap::new();
+
+        for index in 0..o.stages.len() {
+            if files.is_empty() {
+                return Ok(());
+            }
+
+            let stage = Stage::Partial(o.stages[index].scan);
+            let before = candidate_stats(&files).0;
+            let mut io = StageIo::default();
+            progress(&Progress::StageStarted(stage));
+            files = eliminate_partial(&table, files, index, &mut covered, o, &mut io, errors, progress)?;
+            finish_stage(report, stage, before, &files, io, progress);
+        }
+
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let before = candidate_stats(&files).0;
+        let mut io = StageIo::default();
+        progress(&Progress::StageStarted(Stage::Full));
+
+        for ((fsize, checksum), indices) in hash_candidates(&table, files, o, &mut io, errors, progress)? {
+            let mut files: Vec<FileEntry> = indices.into_iter().map(|i| table.entry(i)).collect();
+            o.keep.sort(&mut files);
+
+            report.groups.push(DuplicateGroup {
+                size: fsize,
+                checksum,
+                files,
+            });
+        }
+
+        let file_count: u64 = report.groups.iter().map(|g| g.files.len() as u64).sum();
+        let stats = StageStats {
+            stage: Stage::Full,
+            file_count,
+            total_size: report.groups.iter().map(|g| g.size * g.files.len() as u64).sum(),
+            bytes_read: io.read,
+            bytes_saved: io.saved,
+            eliminated: before - file_count,
+        };
+        progress(&Progress::StageFinished(&stats));
+        report.stages.push(stats);
+
+        Ok(())
+    }
+}
+
+fn finish_stage<F: FnMut(&Progress)>(
+    report: &mut DuplicateReport,
+    stage: Stage,
+    before: u64, // number of candidates before the stage
+    l: &Candidates,
+    io: StageIo,
+    progress: &mut F,
+) {
+    let (file_count, total_size) = candidate_stats(l);
+    let stats = StageStats {
+        stage,
+        file_count,
+        total_size,
+        bytes_read: io.read,
+        bytes_saved: io.saved,
+        eliminated: before - file_count,
+    };
+    progress(&Progress::StageFinished(&stats));
+    report.stages.push(stats);
+}
 
 // Generate stats from list of files
-pub fn generate_stats(l: HashMap<u64, Vec<PathBuf>>) -> (u64, u64) {
+fn candidate_stats(l: &Candidates) -> (u64, u64) {
     let mut file_count: u64 = 0;
     let mut total_size: u64 = 0;
 
@@ -21,132 +594,339 @@ pub fn generate_stats(l: HashMap<u64, Vec<PathBuf>>) -> (u64, u64) {
     (file_count, total_size)
 }
 
-// Find possible duplicates based on last or first bytes of files
-pub fn eliminate_first_or_last_bytes_hash(
-    l: HashMap<u64, Vec<PathBuf>>,     // List of files
-    t: ScanType, // Scan first or last bytes of file
-    scansize: u64, // how many bytes to scan
-    min_count: u64, // minimal count considered as duplicate (2 or more)
-) -> io::Result<HashMap<u64, Vec<PathBuf>>> {
-    if min_count < 2 {
-        panic!("count < 2")
-    }
+// Find possible duplicates based on last, first or sampled bytes of files
+#[allow(clippy::too_many_arguments)]
+fn eliminate_partial<F: FnMut(&Progress)>(
+    table: &FileTable, // Scanned files
+    l: Candidates,     // List of files
+    index: usize, // index of partial stage in ScanOptions::stages
+    covered: &mut HashMap<u64, u64>, // bytes read from each file of a size by partial stages so far
+    o: &ScanOptions, // scan size, minimal count considered as duplicate and number of threads
+    io: &mut StageIo, // bytes read and saved
+    errors: &mut ErrorLog, // unreadable files are dropped and recorded here when skipping errors
+    progress: &mut F, // per file progress
+) -> Result<Candidates> {
+    // Scan first, last or sampled bytes of file
+    let t = o.stages[index].scan;
+    let stage = Stage::Partial(t);
 
     // used for generating a new list of candidate files
-    let mut newl: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    let mut newl: Candidates = HashMap::new();
+
+    // Scan size of each size group
+    let mut scansizes: HashMap<u64, u64> = HashMap::new();
+
+    // Files to hash, grouped by size
+    let mut work: Vec<(u64, FileIndex)> = Vec::new();
+
+    let mut l: Vec<(u64, Vec<FileIndex>)> = l.into_iter().collect();
+    l.sort_unstable_by_key(|(fsize, _)| *fsize);
 
     for (fsize, files) in l {
-        if fsize <= scansize {
-            // File is too small for last/first bytes hashing
+        let file_count = files.len() as u64;
+        let s = o.partial_scansize(&o.stages[index], fsize, file_count);
+        // Bytes read by earlier partial stages which weren't skipped for this size, and this stage
+        let read = covered.get(&fsize).copied().unwrap_or(0) + t.read_size(s);
+
+        if read.saturating_mul(2) > fsize {
+            // File is too small for last/first bytes hashing, partial stages together
+            // would read more than half of it before full hashing reads all of it
             // Send for later processing
+            io.saved += fsize.min(t.read_size(s)) * file_count;
             newl.insert(fsize, files);
             continue;
         }
 
-        let mut hashes: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        progress(&Progress::ScanningGroup {
+            scan: t,
+            size: fsize,
+            file_count,
+            scansize: s,
+        });
 
-        for file in files {
-            let checksum = hash_partial(file.to_owned(), t, scansize)?;
+        covered.insert(fsize, read);
+        scansizes.insert(fsize, s);
+        work.extend(files.into_iter().map(|file| (fsize, file)));
+    }
 
-            hashes
-                .entry(checksum)
-                .or_default()
-                .push(file);
-        }
+    if o.physical_order {
+        sort_physical(table, &mut work, |fsize| t.offsets(fsize, scansizes[&fsize])[0], o)?;
+    }
 
-        for (_, filelist) in hashes {
-            if filelist.is_empty() {
-                continue;
-            }
+    let hashes = hash_files(table, work, o, |p, fsize| hash_partial(p, fsize, t, scansizes[&fsize], o.partial_hash), stage, errors, progress)?;
 
-            if filelist.len() < min_count as usize {
-                // Remove if there's too few files with same hash
-                continue;
-            }
+    for ((fsize, _), filelist) in hashes {
+        io.read += t.read_size(scansizes[&fsize]) * filelist.len() as u64;
 
-            for fpath in filelist {
-                newl
-                    .entry(fsize)
-                    .or_default()
-                    .push(fpath);
+        if filelist.len() < o.count as usize {
+            // Remove if there's too few files with same hash
+            for &file in &filelist {
+                progress(&Progress::Eliminated {
+                    stage,
+                    path: &table.path(file),
+                });
             }
+
+            continue;
         }
+
+        newl
+            .entry(fsize)
+            .or_default()
+            .extend(filelist);
     }
 
     Ok(newl)
 }
 
-// Find initial candidates from given path(s)
-pub fn find_candidate_files(
-    paths: Vec<PathBuf>, // file path(s) to scan for files
-    minimum_size: u64, // file size must be at least this
-    maximum_size: u64, // file size cannot be larger than this, 0 disables max size
-    count: u64, // there must be at least this many files with same file size to be considered a duplicate (must be 2 or more)
-) -> io::Result<HashMap<u64, Vec<PathBuf>>> {
-    if count < 2 {
-        panic!("count < 2")
+// Hash files on worker threads, files are grouped by size and checksum in a sorted list
+fn hash_files<H, F>(
+    table: &FileTable, // Scanned files
+    work: Vec<(u64, FileIndex)>, // files to hash with their sizes, in read order
+    o: &ScanOptions, // number of worker threads and readers per device
+    hash: H, // hash function
+    stage: Stage, // stage reported in progress
+    errors: &mut ErrorLog, // unreadable files are dropped and recorded here when skipping errors
+    progress: &mut F, // per file progress
+) -> Result<Checksums>
+    where H: Fn(&Path, u64) -> Result<String> + Sync, F: FnMut(&Progress) {
+    let mut hashes: HashMap<(u64, String), Vec<FileIndex>> = HashMap::new();
+    let mut devices = Devices::default();
+
+    // Number of files in each size group not reported yet
+    let mut groups: HashMap<u64, u64> = HashMap::new();
+
+    if stage == Stage::Full {
+        for &(fsize, _) in &work {
+            *groups.entry(fsize).or_default() += 1;
+        }
     }
 
-    let mut found_inodes: Vec<u64> = Vec::new();
+    pool::run(
+        o.threads,
+        &work,
+        |&(_, file)| devices.queue(table.id(file).dev, &o.readers),
+        |&(fsize, file)| {
+            let path = table.path(file);
+            let checksum = hash(&path, fsize);
+            (path, checksum)
+        },
+        |i, (path, checksum)| {
+            let (fsize, file) = work[i];
+
+            if let Some(file_count) = groups.remove(&fsize) {
+                progress(&Progress::HashingGroup {
+                    size: fsize,
+                    file_count,
+                });
+            }
+
+            let checksum = match checksum {
+                Ok(r) => r,
+                Err(e) => return errors.handle(e),
+            };
+
+            progress(&Progress::Hashed {
+                stage,
+                path: &path,
+                checksum: &checksum,
+            });
+
+            hashes
+                .entry((fsize, checksum))
+                .or_default()
+                .push(file);
+
+            Ok(())
+        },
+    )?;
+
+    let mut hashes: Checksums = hashes.into_iter().collect();
+    hashes.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(hashes)
+}
+
+// Sort files on rotational hard drives by the position on disk where the stage starts reading them,
+// files on other devices keep their order and are read first
+fn sort_physical<S: Fn(u64) -> u64 + Sync>(
+    table: &FileTable, // Scanned files
+    work: &mut Vec<(u64, FileIndex)>, // files to hash with their sizes
+    start: S, // offset where reading starts in a file of given size
+    o: &ScanOptions, // number of worker threads and readers per device
+) -> Result<()> {
+    let mut devices = Devices::default();
+    let rotational: HashSet<u64> = work
+        .iter()
+        .map(|&(_, file)| table.id(file).dev)
+        .filter(|&dev| devices.rotational(dev) == Some(true))
+        .collect();
+
+    if rotational.is_empty() {
+        return Ok(());
+    }
+
+    let mut keys: Vec<Option<(u64, Option<u64>)>> = Vec::with_capacity(work.len());
+
+    pool::run(
+        o.threads,
+        work,
+        |&(_, file)| devices.queue(table.id(file).dev, &o.readers),
+        |&(fsize, file)| {
+            let dev = table.id(file).dev;
 
-    // l[filesize][]filepath
-    let mut sizes: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+            if !rotational.contains(&dev) {
+                return None;
+            }
+
+            Some((dev, fiemap::physical_offset(&table.path(file), start(fsize)).ok().flatten()))
+        },
+        |_, key| {
+            keys.push(key);
+            Ok(())
+        },
+    )?;
+
+    order_physical(work, &keys);
+    Ok(())
+}
+
+// Order items by their device and position on disk, None for items not on rotational disks.
+// Items not on rotational disks keep their order and come first, items without known position come last.
+fn order_physical<T: Copy>(items: &mut Vec<T>, keys: &[Option<(u64, Option<u64>)>]) {
+    let mut order: Vec<usize> = (0..items.len()).collect();
+    order.sort_by_key(|&i| keys[i].map(|(dev, offset)| (dev, offset.unwrap_or(u64::MAX))));
+    *items = order.into_iter().map(|i| items[i]).collect();
+}
 
-    for path in paths {
-        for entry in WalkDir::new(path)
+// Find initial candidates from given path(s)
+fn collect_candidates<F: FnMut(&Progress)>(
+    paths: &[PathBuf], // file path(s) to scan for files
+    minimum_size: u64, // file size must be at least this
+    maximum_size: u64, // file size cannot be larger than this
+    count: u64, // there must be at least this many files with same file size to be considered a duplicate (must be 2 or more)
+    sort_order: SortOrder, // order of directory entries
+    errors: &mut ErrorLog, // unreadable files and directories are recorded here when skipping errors
+    progress: &mut F, // per file progress
+) -> Result<(FileTable, Candidates)> {
+    let mut table = FileTable::default();
+
+    // Files on different file systems can share i-node numbers.
+    // Every file is remembered: files given as paths and bind mounted files can be found
+    // again even when they have a single link.
+    let mut found_ids: HashSet<FileId> = HashSet::new();
+    let mut found_dirs: HashSet<FileId> = HashSet::new();
+
+    // l[filesize][]file
+    let mut sizes: Candidates = HashMap::new();
+
+    for (root, path) in paths.iter().enumerate() {
+        // Table index of each directory on the current path, by depth
+        let mut dirs: Vec<u32> = Vec::new();
+
+        let mut it = WalkDir::new(path)
             .follow_links(false)
             .same_file_system(true)
-            .sort_by(|a, b|
-                a.ino().cmp(&b.ino())
-            ) {
-            let e = entry?;
+            .sort_by(move |a, b| sort_order.compare(a, b))
+            .into_iter();
+
+        while let Some(entry) = it.next() {
+            let e = match entry {
+                Ok(r) => r,
+                Err(err) => {
+                    errors.handle(Error::walk(path, err))?;
+                    continue;
+                }
+            };
 
             if e.file_type().is_symlink() {
+                progress(&Progress::Skipped {
+                    path: e.path(),
+                    reason: SkipReason::Symlink,
+                });
                 continue;
             }
 
             if e.file_type().is_dir() {
-                // Only files
+                let m = match e.metadata() {
+                    Ok(r) => r,
+                    Err(err) => {
+                        errors.handle(Error::walk(path, err))?;
+                        it.skip_current_dir();
+                        continue;
+                    }
+                };
+
+                if !found_dirs.insert(FileId::from_metadata(&m)) {
+                    progress(&Progress::Skipped {
+                        path: e.path(),
+                        reason: SkipReason::SameDirectory,
+                    });
+                    it.skip_current_dir();
+                    continue;
+                }
+
+                dirs.truncate(e.depth());
+                dirs.push(table.add_dir(e.path()));
                 continue;
             }
 
             if !e.file_type().is_file() {
                 // Only files
+                progress(&Progress::Skipped {
+                    path: e.path(),
+                    reason: SkipReason::NotFile,
+                });
                 continue;
             }
 
-            let m = e.metadata()?;
-            if m.len() == 0 {
+            let m = match e.metadata() {
+                Ok(r) => r,
+                Err(err) => {
+                    errors.handle(Error::walk(path, err))?;
+                    continue;
+                }
+            };
+            let skip = if m.len() == 0 {
                 // Zero sized file, skip
-                continue;
-            }
-
-            if m.len() < minimum_size {
+                Some(SkipReason::Empty)
+            } else if m.len() < minimum_size {
                 // Too small file
-                continue;
-            }
-
-            if m.len() > maximum_size {
+                Some(SkipReason::TooSmall)
+            } else if m.len() > maximum_size {
                 // Too large file
-                continue;
-            }
-
-            if found_inodes.contains(&e.ino()) {
+                Some(SkipReason::TooLarge)
+            } else if !found_ids.insert(FileId::from_metadata(&m)) {
                 // Existing file with same inode, skip
+                Some(SkipReason::SameInode)
+            } else {
+                None
+            };
+
+            if let Some(reason) = skip {
+                progress(&Progress::Skipped {
+                    path: e.path(),
+                    reason,
+                });
                 continue;
             }
 
-            found_inodes.push(e.ino().to_owned());
+            let dir = match e.depth() {
+                // Scanned path is a file
+                0 => table.add_dir(e.path().parent().unwrap_or(Path::new(""))),
+                d => dirs[d - 1],
+            };
+
+            let file = table.add_file(dir, e.file_name(), root, e.depth(), &m);
 
             sizes
                 .entry(m.len())
                 .or_default()
-                .push(e.into_path());
+                .push(file);
         }
     }
 
     // Filter out file groups which has too few files
-    let mut files: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    let mut files: Candidates = HashMap::new();
 
     for (k, v) in sizes {
         if v.is_empty() {
@@ -155,146 +935,1429 @@ pub fn find_candidate_files(
 
         if v.len() < count as usize {
             // Too few files to be considered duplicate
+            for &file in &v {
+                progress(&Progress::Eliminated {
+                    stage: Stage::Size,
+                    path: &table.path(file),
+                });
+            }
+
             continue;
         }
 
         files.entry(k).or_insert(v);
     }
 
+    Ok((table, files))
+}
 
-    Ok(files)
+// Order of directory entries while walking directories
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    // I-node number, usually close to the order of data on disk
+    Inode,
+    // File name
+    Filename,
+    // Files before subdirectories, then file name
+    Depth,
 }
 
-#[derive(Clone, Copy)]
+impl SortOrder {
+    pub const ALL: [SortOrder; 3] = [SortOrder::Inode, SortOrder::Filename, SortOrder::Depth];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortOrder::Inode => "i-node",
+            SortOrder::Filename => "filename",
+            SortOrder::Depth => "depth",
+        }
+    }
+
+    fn compare(&self, a: &DirEntry, b: &DirEntry) -> Ordering {
+        match self {
+            SortOrder::Inode => a.ino().cmp(&b.ino()),
+            SortOrder::Filename => a.file_name().cmp(b.file_name()),
+            SortOrder::Depth => a.file_type().is_dir().cmp(&b.file_type().is_dir())
+                .then_with(|| a.file_name().cmp(b.file_name())),
+        }
+    }
+}
+
+impl fmt::Display for SortOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match SortOrder::ALL.iter().find(|o| o.name() == s) {
+            Some(o) => Ok(*o),
+            None => Err(format!("unknown sort order: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ScanType {
     // Scan first N bytes
     First,
     // Scan last N bytes
     Last,
+    // Scan N bytes from the middle
+    Middle,
+    // Scan given number of evenly spaced blocks of N bytes between the beginning and the end
+    Sampled(u64),
 }
 
-fn checksum_to_hex(bytes: &[u8]) -> String {
-    let mut s = String::with_capacity(bytes.len() * 2);
+impl ScanType {
+    // How many bytes are scanned from each file, with scan size s
+    pub fn read_size(&self, s: u64) -> u64 {
+        match self {
+            ScanType::Sampled(n) => s.saturating_mul((*n).max(1)),
+            _ => s,
+        }
+    }
 
-    for &b in bytes {
-        write!(&mut s, "{:02x}", b).unwrap();
+    // Offsets of the blocks scanned from a file of given size, with scan size s
+    pub fn offsets(&self, size: u64, s: u64) -> Vec<u64> {
+        let last = size.saturating_sub(s);
+
+        match self {
+            ScanType::First => vec![0],
+            ScanType::Last => vec![last],
+            ScanType::Middle => ScanType::Sampled(1).offsets(size, s),
+            ScanType::Sampled(n) => {
+                let n = (*n).max(1);
+
+                // Centers of the blocks divide the file in n + 1 equal parts
+                (1..=n)
+                    .map(|i| ((i as u128 * size as u128 / (n as u128 + 1)) as u64).saturating_sub(s / 2).min(last))
+                    .collect()
+            }
+        }
     }
+}
 
-    s
+// Partial hashing stage of the elimination pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartialStage {
+    pub scan: ScanType,
+    // Bytes to scan per block, None uses ScanOptions::scansize, see ScanOptions::partial_scansize
+    pub size: Option<u64>,
 }
 
-// Hash file partially from beginning or end
-fn hash_partial(
-    p: PathBuf, // File to scan
-    t: ScanType, // Scan first or last bytes of file
-    s: u64, // how many bytes to scan
-) -> io::Result<String> {
-    if s == 0 {
-        panic!("zero size")
+impl From<ScanType> for PartialStage {
+    fn from(scan: ScanType) -> Self {
+        PartialStage { scan, size: None }
     }
+}
 
-    let mut f = File::open(p)?;
-
-    match t {
-        ScanType::First => {
-            // Do nothing
+impl fmt::Display for PartialStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scan {
+            ScanType::First => write!(f, "first")?,
+            ScanType::Last => write!(f, "last")?,
+            ScanType::Middle => write!(f, "middle")?,
+            ScanType::Sampled(n) => write!(f, "sample:{}", n)?,
         }
-        ScanType::Last => {
-            // Seek from end position
-            f.seek(SeekFrom::End(-(s as i64)))?;
+
+        match (self.scan, self.size) {
+            (_, None) => Ok(()),
+            (ScanType::Sampled(_), Some(size)) => write!(f, "x{}", size),
+            (_, Some(size)) => write!(f, ":{}", size),
         }
     }
+}
 
-    let mut buffer: Vec<u8> = iter::repeat(0u8).take(s as usize).collect();
-    let mut reader = BufReader::new(f);
-    let mut hasher = Sha512::new();
+// first, last or middle with optional size (first:4KiB), or sample:N with optional size (sample:8x64KiB)
+impl FromStr for PartialStage {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse_scansize = |size: &str| match parse_size::parse_size(size) {
+            Ok(0) => Err(format!("scan size must be 1 or more: {}", s)),
+            Ok(size) => Ok(size),
+            Err(_) => Err(format!("invalid scan size: {}", s)),
+        };
+
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+
+        let scan = match name {
+            "first" => ScanType::First,
+            "last" => ScanType::Last,
+            "middle" => ScanType::Middle,
+            "sample" => {
+                let Some(arg) = arg else {
+                    return Err(format!("number of sampled blocks missing, e.g. sample:8x64KiB: {}", s));
+                };
+
+                let (n, size) = match arg.split_once('x') {
+                    Some((n, size)) => (n, Some(parse_scansize(size)?)),
+                    None => (arg, None),
+                };
+
+                return match n.parse::<u64>() {
+                    Ok(n) if n > 0 => Ok(PartialStage { scan: ScanType::Sampled(n), size }),
+                    _ => Err(format!("invalid number of sampled blocks: {}", s)),
+                };
+            }
+            _ => return Err(format!("unknown stage: {}", s)),
+        };
+
+        Ok(PartialStage {
+            scan,
+            size: arg.map(parse_scansize).transpose()?,
+        })
+    }
+}
+
+// Parse comma separated elimination pipeline, such as size,first:4KiB,last:1MiB,sample:8x64KiB,full
+// Size grouping must come first and full hashing last, partial stages are returned in between.
+pub fn parse_pipeline(s: &str) -> std::result::Result<Vec<PartialStage>, String> {
+    let stages: Vec<&str> = s.split(',').map(str::trim).collect();
+
+    if stages.first() != Some(&"size") {
+        return Err("pipeline must start with size".to_string());
+    }
 
-    let count = reader.read(&mut buffer)?;
-    if count == 0 {
-        panic!("0????")
+    if stages.len() < 2 || stages.last() != Some(&"full") {
+        return Err("pipeline must end with full".to_string());
     }
-    hasher.update(&buffer[..count]);
 
-    Ok(checksum_to_hex(hasher.finalize().as_slice()))
+    stages[1..stages.len() - 1]
+        .iter()
+        .map(|&s| match s {
+            "size" => Err("size can only be the first stage".to_string()),
+            "full" => Err("full can only be the last stage".to_string()),
+            s => PartialStage::from_str(s),
+        })
+        .collect()
+}
+
+// Adaptive scan size for a size group of file_count files of given size, see ScanOptions::adaptive_scansize.
+// Blocks are 1/64 of the file size, so big files which share headers are still told apart while small
+// files aren't mostly read by partial stages. Larger groups get smaller blocks as each block is read
+// from every file of the group. Sizes are rounded up to 4 KiB pages and kept between 4 KiB and 64 MiB.
+pub fn adaptive_scansize(size: u64, file_count: u64) -> u64 {
+    const PAGE: u64 = 4096;
+    const MAX: u64 = 64 * 1048576;
+
+    // ceil(log2(file_count)), 1 for a pair of files
+    let bits = (64 - file_count.saturating_sub(1).leading_zeros() as u64).max(1);
+
+    (size / 64 / bits).div_ceil(PAGE).saturating_mul(PAGE).clamp(PAGE, MAX)
+}
+
+fn checksum_to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+
+    for &b in bytes {
+        write!(&mut s, "{:02x}", b).unwrap();
+    }
+
+    s
+}
+
+// Hash file partially from beginning, end or sampled blocks
+fn hash_partial(
+    p: &Path, // File to scan
+    size: u64, // File size
+    t: ScanType, // Scan first, last or sampled bytes of file
+    s: u64, // how many bytes to scan per block
+    h: HashAlgorithm, // checksum algorithm
+) -> Result<String> {
+    let stage = Stage::Partial(t);
+    let mut f = File::open(p).map_err(|e| Error::io(p, stage, e))?;
+
+    let mut buffer: Vec<u8> = vec![0u8; s.min(size) as usize];
+    let mut hasher = h.hasher();
+
+    for offset in t.offsets(size, s) {
+        f.seek(SeekFrom::Start(offset)).map_err(|e| Error::io(p, stage, e))?;
+
+        let count = action::read_full(&mut f, &mut buffer).map_err(|e| Error::io(p, stage, e))?;
+        if count == 0 {
+            return Err(Error::EmptyRead {
+                path: p.to_path_buf(),
+                stage,
+            });
+        }
+        hasher.update(&buffer[..count]);
+    }
+
+    Ok(checksum_to_hex(&hasher.finish()))
 }
 
 // Hash the entire file
 fn hash_full(
-    p: PathBuf, // File to scan
-) -> io::Result<String> {
-    let f = File::open(p)?;
+    p: &Path, // File to scan
+    h: HashAlgorithm, // checksum algorithm
+) -> Result<String> {
+    let f = File::open(p).map_err(|e| Error::io(p, Stage::Full, e))?;
 
     let mut buffer = [0u8; 1048576];
     let mut reader = BufReader::new(f);
-    let mut hasher = Sha512::new();
+    let mut hasher = h.hasher();
 
     loop {
-        let count = reader.read(&mut buffer)?;
+        let count = reader.read(&mut buffer).map_err(|e| Error::io(p, Stage::Full, e))?;
         if count == 0 { break; }
         hasher.update(&buffer[..count]);
     }
 
-    Ok(checksum_to_hex(hasher.finalize().as_slice()))
+    Ok(checksum_to_hex(&hasher.finish()))
 }
 
-// Hashes files fully and returns file list and checksum as the key
-pub fn find_final_candidates(
-    l: Vec<PathBuf>,     // List of files
-) -> io::Result<HashMap<String, Vec<PathBuf>>> {
-    let mut res: HashMap<String, Vec<PathBuf>> = HashMap::new();
-    let mut hashes: HashMap<String, Vec<PathBuf>> = HashMap::new();
+// Hashes files fully and returns file list with size and checksum as the key, sorted by key.
+// Small groups are compared in lockstep instead, see ScanOptions::compare
+fn hash_candidates<F: FnMut(&Progress)>(
+    table: &FileTable, // Scanned files
+    l: Candidates,     // List of files
+    o: &ScanOptions, // minimal count considered as duplicate and number of threads
+    io: &mut StageIo, // bytes read and saved
+    errors: &mut ErrorLog, // unreadable files are dropped and recorded here when skipping errors
+    progress: &mut F, // per file progress
+) -> Result<Checksums> {
+    let mut work: Vec<(u64, FileIndex)> = Vec::new();
+    let mut small: Vec<(u64, Vec<FileIndex>)> = Vec::new();
+
+    let mut l: Vec<(u64, Vec<FileIndex>)> = l.into_iter().collect();
+    l.sort_unstable_by_key(|(fsize, _)| *fsize);
 
-    for file in l {
-        let checksum = hash_full(file.to_owned())?;
+    for (fsize, files) in l {
+        if files.len() <= o.compare.min(MAX_COMPARE) {
+            small.push((fsize, files));
+            continue;
+        }
 
+        work.extend(files.into_iter().map(|file| (fsize, file)));
+    }
 
-        hashes
-            .entry(checksum)
-            .or_default()
-            .push(file);
+    if o.physical_order {
+        sort_physical(table, &mut work, |_| 0, o)?;
     }
 
+    let mut hashes = hash_files(table, work, o, |p, _| hash_full(p, o.hash), Stage::Full, errors, progress)?;
+    io.read += hashes.iter().map(|((fsize, _), files)| fsize * files.len() as u64).sum::<u64>();
+
+    let mut devices = Devices::default();
+
+    pool::run(
+        o.threads,
+        &small,
+        |(_, files)| {
+            // All files of the group are read together, a group with files on a spinning disk is queued there
+            let dev = files
+                .iter()
+                .map(|&f| table.id(f).dev)
+                .find(|&dev| devices.rotational(dev) == Some(true))
+                .unwrap_or(table.id(files[0]).dev);
+            devices.queue(dev, &o.readers)
+        },
+        |(_, files)| {
+            let paths: Vec<PathBuf> = files.iter().map(|&f| table.path(f)).collect();
+            let sets = compare_files(&paths, o.hash);
+            (paths, sets)
+        },
+        |i, (paths, (sets, errs, read))| {
+            let (fsize, files) = &small[i];
+
+            io.read += read;
+            io.saved += (fsize * files.len() as u64).saturating_sub(read);
+
+            progress(&Progress::ComparingGroup {
+                size: *fsize,
+                file_count: files.len() as u64,
+            });
+
+            for e in errs {
+                errors.handle(e)?;
+            }
+
+            for (checksum, set) in sets {
+                let Some(checksum) = checksum else {
+                    // Differs from every other file
+                    for &k in &set {
+                        progress(&Progress::Eliminated {
+                            stage: Stage::Full,
+                            path: &paths[k],
+                        });
+                    }
+
+                    continue;
+                };
+
+                for &k in &set {
+                    progress(&Progress::Hashed {
+                        stage: Stage::Full,
+                        path: &paths[k],
+                        checksum: &checksum,
+                    });
+                }
+
+                hashes.push(((*fsize, checksum), set.into_iter().map(|k| files[k]).collect()));
+            }
+
+            Ok(())
+        },
+    )?;
+
+    hashes.sort_unstable_by(|a, b| a.0.cmp(&b.0));
 
     // Filter out file groups which has too few files
-    for (checksum, files) in hashes {
-        if files.is_empty() {
-            continue;
+    hashes.retain(|(_, files)| {
+        if files.len() < o.count as usize {
+            // Each file with same checksum must have enough files to be considered duplicate
+            for &file in files {
+                progress(&Progress::Eliminated {
+                    stage: Stage::Full,
+                    path: &table.path(file),
+                });
+            }
+
+            return false;
         }
 
-        if files.len() < 2 {
-            // Each file with same checksum must have 2 or more files to be considered duplicate
+        true
+    });
+
+    Ok(hashes)
+}
+
+// How many bytes are compared at a time in lockstep comparison
+const COMPARE_BLOCK: usize = 1048576;
+
+// Sets of files with identical contents, by index, with their checksum if the set has several files
+type ContentSets = Vec<(Option<String>, Vec<usize>)>;
+
+// Files with identical contents so far in lockstep comparison
+struct Lockstep {
+    files: Vec<(usize, File)>,
+    // Checksum of the contents read so far
+    hasher: Box<dyn Hasher + Send>,
+}
+
+// Compare files of same size by reading them block by block in lockstep.
+// Files are split into sets as soon as their contents diverge, so files without duplicates are rejected
+// after the first differing block. Returns sets of identical files (indices of paths) with the checksum
+// of their contents, files without a match as sets of one file without checksum, unreadable files
+// and the number of bytes read.
+fn compare_files(
+    paths: &[PathBuf], // Files to compare
+    h: HashAlgorithm, // checksum algorithm
+) -> (ContentSets, Vec<Error>, u64) {
+    let mut done: ContentSets = Vec::new();
+    let mut bytes_read: u64 = 0;
+    let mut errors: Vec<Error> = Vec::new();
+    let mut files: Vec<(usize, File)> = Vec::new();
+
+    for (i, p) in paths.iter().enumerate() {
+        match File::open(p) {
+            Ok(f) => files.push((i, f)),
+            Err(e) => errors.push(Error::io(p, Stage::Full, e)),
+        }
+    }
+
+    let mut buffers: Vec<Vec<u8>> = vec![Vec::new(); paths.len()];
+    let mut sets = vec![Lockstep {
+        files,
+        hasher: h.hasher(),
+    }];
+
+    while let Some(set) = sets.pop() {
+        if set.files.len() < 2 {
+            done.push((None, set.files.into_iter().map(|(i, _)| i).collect()));
             continue;
         }
 
-        res.insert(checksum, files);
+        // Read next block of each file
+        let mut read: Vec<(usize, File, usize)> = Vec::new();
+
+        for (i, mut f) in set.files {
+            buffers[i].resize(COMPARE_BLOCK, 0);
+
+            match action::read_full(&mut f, &mut buffers[i]) {
+                Ok(count) => {
+                    bytes_read += count as u64;
+                    read.push((i, f, count));
+                }
+                Err(e) => errors.push(Error::io(&paths[i], Stage::Full, e)),
+            }
+        }
+
+        // Split files by the contents of the block
+        let mut parts: Vec<Vec<(usize, File, usize)>> = Vec::new();
+
+        for (i, f, count) in read {
+            let same = parts.iter_mut().find(|p| {
+                let (j, _, c) = p[0];
+                buffers[j][..c] == buffers[i][..count]
+            });
+
+            match same {
+                Some(p) => p.push((i, f, count)),
+                None => parts.push(vec![(i, f, count)]),
+            }
+        }
+
+        for part in parts {
+            let (first, _, count) = part[0];
+
+            if part.len() < 2 {
+                done.push((None, vec![first]));
+                continue;
+            }
+
+            // Sets continue from the same checksum state
+            let mut hasher = set.hasher.clone_box();
+
+            if count == 0 {
+                // End of files
+                done.push((Some(checksum_to_hex(&hasher.finish())), part.into_iter().map(|(i, _, _)| i).collect()));
+                continue;
+            }
+
+            hasher.update(&buffers[first][..count]);
+
+            sets.push(Lockstep {
+                files: part.into_iter().map(|(i, f, _)| (i, f)).collect(),
+                hasher,
+            });
+        }
+    }
+
+    done.sort_unstable_by_key(|(_, set)| set[0]);
+    (done, errors, bytes_read)
+}
+
+
+#[test]
+fn test_integration() -> Result<()> {
+    let report = DuplicateFinder::new()
+        .path("test")
+        .count(2)
+        .scansize(1048576)
+        .run()?;
+
+    assert_eq!(report.groups.len(), 1);
+    assert_eq!(report.groups[0].size, 1000);
+    assert_eq!(report.groups[0].files.len(), 3);
+
+    for group in report.groups {
+        for file in group.files {
+            println!("{}", file.path.display())
+        }
     }
 
-    Ok(res)
+    Ok(())
 }
 
+#[test]
+#[allow(deprecated)]
+fn test_legacy_api() -> std::io::Result<()> {
+    let files = find_candidate_files(vec![PathBuf::from("test")], 1, u64::MAX, 2)?;
+    assert_eq!(generate_stats(files.clone()), (3, 3000));
+
+    let files = eliminate_first_or_last_bytes_hash(files, ScanType::Last, 100, 2)?;
+    let files = eliminate_first_or_last_bytes_hash(files, ScanType::First, 100, 2)?;
+    assert_eq!(generate_stats(files.clone()), (3, 3000));
+
+    let groups = find_final_candidates(files.into_values().flatten().collect())?;
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups.values().next().unwrap().len(), 3);
+
+    assert!(find_candidate_files(vec![PathBuf::from("test")], 1, u64::MAX, 1).is_err());
+    assert!(eliminate_first_or_last_bytes_hash(HashMap::new(), ScanType::Last, 100, 1).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_options() {
+    let r = DuplicateFinder::new().path("test").count(1).run();
+    assert!(matches!(r, Err(Error::InvalidCount(1))));
+
+    let r = DuplicateFinder::new().path("test").scansize(0).run();
+    assert!(matches!(r, Err(Error::ZeroScanSize)));
+
+    let r = DuplicateFinder::new().path("test").hash(HashAlgorithm::Xxh3).run();
+    assert!(matches!(r, Err(Error::WeakHash(HashAlgorithm::Xxh3))));
+}
 
 #[test]
-fn test_integration() -> io::Result<()> {
-    let mincount: u64 = 2;
-    let scansize: u64 = 1048576;
+fn test_hash_algorithms() -> Result<()> {
+    for (hash, len) in [(HashAlgorithm::Sha512, 128), (HashAlgorithm::Sha256, 64), (HashAlgorithm::Blake3, 64)] {
+        let report = DuplicateFinder::new()
+            .path("test")
+            .scansize(100)
+            .hash(hash)
+            .partial_hash(HashAlgorithm::Xxh3)
+            .run()?;
+
+        assert_eq!(report.hash, hash);
+        assert_eq!(report.partial_hash, HashAlgorithm::Xxh3);
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].files.len(), 3);
+        assert_eq!(report.groups[0].checksum.len(), len);
+    }
 
-    let mut paths: Vec<PathBuf> = Vec::new();
-    paths.push(Path::new("test").to_path_buf());
+    Ok(())
+}
 
-    let mut cf = find_candidate_files(paths, 1, 0, mincount)?;
-    cf = eliminate_first_or_last_bytes_hash(cf, ScanType::Last, scansize, mincount)?;
-    cf = eliminate_first_or_last_bytes_hash(cf, ScanType::First, scansize, mincount)?;
+#[test]
+fn test_skip_errors() -> Result<()> {
+    let r = DuplicateFinder::new().path("test").path("test/missing").run();
+    assert!(matches!(r, Err(Error::Io { stage: Stage::Size, .. })));
 
-    for (fsize, files) in cf {
-        let final_candidates = find_final_candidates(files)?;
+    let report = DuplicateFinder::new()
+        .path("test")
+        .path("test/missing")
+        .skip_errors(true)
+        .run()?;
+
+    assert_eq!(report.groups.len(), 1);
+    assert_eq!(report.errors.len(), 1);
+    assert_eq!(report.errors[0].path(), Some(Path::new("test/missing")));
+
+    Ok(())
+}
+
+// Create empty temporary directory for a test
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("samanlainen-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_keep_priority() -> Result<()> {
+    use std::time::Duration;
+
+    let dir = test_dir("keep-priority");
+    let data = std::fs::read("test/random.dat").unwrap();
+
+    for (name, age) in [("a", 100), ("b", 50), ("c", 200)] {
+        let path = dir.join(name).join("file.dat");
+        std::fs::create_dir(dir.join(name)).unwrap();
+        std::fs::write(&path, &data).unwrap();
+        File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+    }
+
+    // b has highest priority although it's the newest file
+    let report = DuplicateFinder::new()
+        .path(dir.join("b"))
+        .path(dir.join("a"))
+        .path(dir.join("c"))
+        .run()?;
+    assert_eq!(report.groups[0].keep().path, dir.join("b").join("file.dat"));
+
+    // Same priority, oldest is kept
+    let report = DuplicateFinder::new().path(&dir).run()?;
+    assert_eq!(report.groups[0].keep().path, dir.join("c").join("file.dat"));
+
+    let report = DuplicateFinder::new()
+        .path(&dir)
+        .keep(KeepPolicy::new(vec![KeepRule::Newest]))
+        .run()?;
+    assert_eq!(report.groups[0].keep().path, dir.join("b").join("file.dat"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_keep_rules() {
+    let entry = |path: &str, depth: usize, links: u64| FileEntry {
+        path: PathBuf::from(path),
+        id: FileId { dev: 1, ino: links },
+        size: 1000,
+        root: 0,
+        depth,
+        modified: UNIX_EPOCH,
+        links,
+    };
+
+    let mut files = vec![
+        entry("/data/photos/2020/summer/img.jpg", 4, 1),
+        entry("/data/zz/img.jpg", 2, 3),
+        entry("/data/photos/a.jpg", 1, 2),
+    ];
+    let keep = |rule: KeepRule, files: &mut Vec<FileEntry>| {
+        KeepPolicy::new(vec![rule]).sort(files);
+        files.iter().map(|f| f.path.to_str().unwrap().to_string()).collect::<Vec<_>>()
+    };
+
+    assert_eq!(keep(KeepRule::ShortestPath, &mut files), ["/data/zz/img.jpg", "/data/photos/a.jpg", "/data/photos/2020/summer/img.jpg"]);
+    assert_eq!(keep(KeepRule::Shallowest, &mut files), ["/data/photos/a.jpg", "/data/zz/img.jpg", "/data/photos/2020/summer/img.jpg"]);
+    assert_eq!(keep(KeepRule::MostLinks, &mut files), ["/data/zz/img.jpg", "/data/photos/a.jpg", "/data/photos/2020/summer/img.jpg"]);
+
+    // Link count is kept by the file table
+    let dir = test_dir("keep-rules");
+    std::fs::copy("test/random.dat", dir.join("a.dat")).unwrap();
+    std::fs::hard_link(dir.join("a.dat"), dir.join("b.dat")).unwrap();
+
+    let mut table = FileTable::default();
+    let d = table.add_dir(&dir);
+    let i = table.add_file(d, std::ffi::OsStr::new("a.dat"), 0, 1, &std::fs::metadata(dir.join("a.dat")).unwrap());
+    assert_eq!(table.entry(i).links, 2);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_hard_link() -> Result<()> {
+    let dir = test_dir("hard-link");
+    std::fs::copy("test/random.dat", dir.join("a.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("b.dat")).unwrap();
+
+    let report = DuplicateFinder::new()
+        .path(&dir)
+        .keep(KeepPolicy::new(vec![KeepRule::Name]))
+        .run()?;
+    let group = &report.groups[0];
+    Action::HardLink.apply(group.keep(), &group.duplicates()[0])?;
+
+    let a = std::fs::metadata(dir.join("a.dat")).unwrap();
+    let b = std::fs::metadata(dir.join("b.dat")).unwrap();
+    assert_eq!(a.ino(), b.ino());
+    assert_eq!(a.nlink(), 2);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_symlink() -> Result<()> {
+    let dir = test_dir("symlink");
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::create_dir_all(dir.join("b").join("c")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("a").join("x.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("b").join("c").join("y.dat")).unwrap();
+
+    let report = DuplicateFinder::new().path(dir.join("a")).path(dir.join("b")).run()?;
+    let group = &report.groups[0];
+    Action::RelativeSymlink.apply(group.keep(), &group.duplicates()[0])?;
+
+    let link = dir.join("b").join("c").join("y.dat");
+    assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("../../a/x.dat"));
+    assert_eq!(std::fs::read(&link).unwrap(), std::fs::read("test/random.dat").unwrap());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_move() -> Result<()> {
+    let dir = test_dir("move");
+    std::fs::create_dir_all(dir.join("scan")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("scan").join("a.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("scan").join("b.dat")).unwrap();
+
+    let report = DuplicateFinder::new()
+        .path(dir.join("scan"))
+        .keep(KeepPolicy::new(vec![KeepRule::Name]))
+        .run()?;
+    let group = &report.groups[0];
+    let quarantine = dir.join("quarantine");
+    let moved = quarantine.join(group.duplicates()[0].path.canonicalize().unwrap().strip_prefix("/").unwrap());
+    Action::Move { dir: quarantine }.apply(group.keep(), &group.duplicates()[0])?;
+
+    assert!(!dir.join("scan").join("b.dat").exists());
+    assert_eq!(std::fs::read(moved).unwrap(), std::fs::read("test/random.dat").unwrap());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_journal_restore() -> Result<()> {
+    let dir = test_dir("journal");
+    std::fs::create_dir_all(dir.join("scan")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("scan").join("a.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("scan").join("b\tc.dat")).unwrap();
+
+    let report = DuplicateFinder::new()
+        .path(dir.join("scan"))
+        .keep(KeepPolicy::new(vec![KeepRule::Name]))
+        .hash(HashAlgorithm::Blake3)
+        .run()?;
+    let group = &report.groups[0];
+    let duplicate = &group.duplicates()[0];
+    let action = Action::Delete;
+    let destination = action.apply(group.keep(), duplicate)?;
+
+    let entry = JournalEntry {
+        timestamp: UNIX_EPOCH + std::time::Duration::from_secs(1700000000),
+        action: action.name().to_string(),
+        size: group.size,
+        hash: report.hash,
+        checksum: group.checksum.clone(),
+        kept: group.keep().path.clone(),
+        path: duplicate.path.clone(),
+        destination,
+    };
+    Journal::create(dir.join("journal"))?.write(&entry)?;
+
+    let entries = Journal::read(dir.join("journal"))?;
+    assert_eq!(entries, vec![entry]);
+
+    // Version 1 journals have SHA512 checksums
+    std::fs::write(dir.join("journal-v1"), "# samanlainen journal v1\n1700000000\tdelete\t1000\tab\t/a\t/b\t\n").unwrap();
+    assert_eq!(Journal::read(dir.join("journal-v1"))?[0].hash, HashAlgorithm::Sha512);
+    assert!(!duplicate.path.exists());
+
+    assert_eq!(entries[0].restore()?, RestoreOutcome::Restored);
+    assert_eq!(std::fs::read(&duplicate.path).unwrap(), std::fs::read("test/random.dat").unwrap());
+
+    // Restored file is independent of the kept file, nothing to do
+    assert_eq!(entries[0].restore()?, RestoreOutcome::Present);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
 
-        for (_, files) in final_candidates {
-            for file in files {
-                println!("{}", file.display())
+#[test]
+fn test_trash() -> Result<()> {
+    let dir = test_dir("trash");
+    std::fs::create_dir_all(dir.join("scan")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("scan").join("a.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("scan").join("b.dat")).unwrap();
+    std::env::set_var("XDG_DATA_HOME", dir.join("data"));
+
+    let report = DuplicateFinder::new()
+        .path(dir.join("scan"))
+        .keep(KeepPolicy::new(vec![KeepRule::Name]))
+        .run()?;
+    let group = &report.groups[0];
+    let destination = Action::Trash.apply(group.keep(), &group.duplicates()[0])?;
+
+    let trash = dir.join("data").join("Trash");
+    assert_eq!(destination, Some(trash.join("files").join("b.dat")));
+    let info = std::fs::read_to_string(trash.join("info").join("b.dat.trashinfo")).unwrap();
+    assert!(info.starts_with("[Trash Info]\nPath=/"));
+    assert!(info.contains("/scan/b.dat\nDeletionDate="));
+
+    let entry = JournalEntry {
+        timestamp: SystemTime::now(),
+        action: Action::Trash.name().to_string(),
+        size: group.size,
+        hash: report.hash,
+        checksum: group.checksum.clone(),
+        kept: group.keep().path.clone(),
+        path: group.duplicates()[0].path.clone(),
+        destination,
+    };
+    assert_eq!(entry.restore()?, RestoreOutcome::Restored);
+    assert!(dir.join("scan").join("b.dat").exists());
+    assert!(!trash.join("info").join("b.dat.trashinfo").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_verify() -> Result<()> {
+    let dir = test_dir("verify");
+    std::fs::copy("test/random.dat", dir.join("a.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("b.dat")).unwrap();
+
+    let report = DuplicateFinder::new().path(&dir).run()?;
+    let group = &report.groups[0];
+    group.verify(&group.duplicates()[0])?;
+
+    // Modified after scanning
+    let mut data = std::fs::read("test/random.dat").unwrap();
+    data[500] ^= 1;
+    std::fs::write(&group.duplicates()[0].path, data).unwrap();
+    assert!(matches!(group.verify(&group.duplicates()[0]), Err(Error::ContentMismatch { .. })));
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_changed_before_action() -> Result<()> {
+    let dir = test_dir("changed");
+    std::fs::copy("test/random.dat", dir.join("a.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("b.dat")).unwrap();
+
+    let report = DuplicateFinder::new()
+        .path(&dir)
+        .keep(KeepPolicy::new(vec![KeepRule::Name]))
+        .run()?;
+    let group = &report.groups[0];
+
+    // Duplicate replaced by another file after scanning
+    std::fs::remove_file(dir.join("b.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("b.dat")).unwrap();
+    let r = Action::Delete.apply(group.keep(), &group.duplicates()[0]);
+    assert!(matches!(r, Err(Error::Changed { .. })));
+    assert!(dir.join("b.dat").exists());
+
+    // Kept file found again through another path
+    std::fs::hard_link(dir.join("a.dat"), dir.join("c.dat")).unwrap();
+    let same = FileEntry {
+        path: dir.join("c.dat"),
+        ..group.keep().clone()
+    };
+    let r = Action::Delete.apply(group.keep(), &same);
+    assert!(matches!(r, Err(Error::SameFile { .. })));
+    assert!(dir.join("c.dat").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_same_file_once() -> Result<()> {
+    let dir = test_dir("same-file");
+    std::fs::copy("test/random.dat", dir.join("a.dat")).unwrap();
+    std::fs::hard_link(dir.join("a.dat"), dir.join("b.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("c.dat")).unwrap();
+
+    let report = DuplicateFinder::new().path(&dir).run()?;
+    let group = &report.groups[0];
+    assert_eq!(group.files.len(), 2);
+    assert_ne!(group.files[0].id, group.files[1].id);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_nested_paths() -> Result<()> {
+    let dir = test_dir("nested");
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("a.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("sub/b.dat")).unwrap();
+
+    // Subdirectory is scanned once, with the priority of the path listed first
+    let report = DuplicateFinder::new().path(dir.join("sub")).path(&dir).run()?;
+    let group = &report.groups[0];
+    assert_eq!(group.files.len(), 2);
+    assert_eq!(group.keep().path, dir.join("sub/b.dat"));
+    assert_eq!(group.keep().root, 0);
+    assert_eq!(group.duplicates()[0].path, dir.join("a.dat"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_file_in_scanned_directory() -> Result<()> {
+    let dir = test_dir("file-root");
+    std::fs::copy("test/random.dat", dir.join("only.dat")).unwrap();
+
+    // Same file found through the directory and as a path of its own is not a duplicate
+    let report = DuplicateFinder::new().path(&dir).path(dir.join("only.dat")).run()?;
+    assert!(report.groups.is_empty());
+
+    let report = DuplicateFinder::new().path(dir.join("only.dat")).path(&dir).run()?;
+    assert!(report.groups.is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_sort_order() -> Result<()> {
+    let dir = test_dir("sort-order");
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::write(dir.join("b.dat"), "small").unwrap();
+    std::fs::write(dir.join("a/x.dat"), "small").unwrap();
+    std::fs::write(dir.join("c.dat"), "small").unwrap();
+
+    // Every file is too small, so skipped files are reported in walk order
+    let walk = |order: SortOrder| -> Result<Vec<PathBuf>> {
+        let mut skipped = Vec::new();
+        DuplicateFinder::new().path(&dir).minimum_size(100).sort_order(order).run_with(|p| {
+            if let Progress::Skipped { path, reason } = p {
+                assert_eq!(*reason, SkipReason::TooSmall);
+                skipped.push(path.strip_prefix(&dir).unwrap().to_path_buf());
             }
+        })?;
+        Ok(skipped)
+    };
+
+    let names = |l: &[&str]| -> Vec<PathBuf> { l.iter().map(PathBuf::from).collect() };
+    assert_eq!(walk(SortOrder::Filename)?, names(&["a/x.dat", "b.dat", "c.dat"]));
+    assert_eq!(walk(SortOrder::Depth)?, names(&["b.dat", "c.dat", "a/x.dat"]));
+
+    let mut inode = walk(SortOrder::Inode)?;
+    inode.sort();
+    assert_eq!(inode, names(&["a/x.dat", "b.dat", "c.dat"]));
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_skip_reasons() -> Result<()> {
+    let dir = test_dir("skip-reasons");
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("a.dat"), "duplicate").unwrap();
+    std::fs::write(dir.join("b.dat"), "duplicate").unwrap();
+    std::fs::hard_link(dir.join("a.dat"), dir.join("c.dat")).unwrap();
+    std::os::unix::fs::symlink(dir.join("a.dat"), dir.join("d.dat")).unwrap();
+    std::fs::write(dir.join("e.dat"), "").unwrap();
+    std::fs::write(dir.join("f.dat"), "tiny").unwrap();
+    std::fs::write(dir.join("g.dat"), vec![1u8; 200]).unwrap();
+    let _socket = std::os::unix::net::UnixListener::bind(dir.join("h.sock")).unwrap();
+    std::fs::write(dir.join("sub/i.dat"), "duplicate").unwrap();
+
+    let mut skipped: Vec<(PathBuf, SkipReason)> = Vec::new();
+    let mut hashed: Vec<PathBuf> = Vec::new();
+
+    // Subdirectory listed as second path was already found under the first one
+    DuplicateFinder::new()
+        .path(&dir)
+        .path(dir.join("sub"))
+        .minimum_size(5)
+        .maximum_size(100)
+        .sort_order(SortOrder::Filename)
+        .run_with(|p| match p {
+            Progress::Skipped { path, reason } => skipped.push((path.strip_prefix(&dir).unwrap().to_path_buf(), *reason)),
+            Progress::Hashed { path, .. } => hashed.push(path.strip_prefix(&dir).unwrap().to_path_buf()),
+            _ => {}
+        })?;
+
+    assert_eq!(skipped, vec![
+        (PathBuf::from("c.dat"), SkipReason::SameInode),
+        (PathBuf::from("d.dat"), SkipReason::Symlink),
+        (PathBuf::from("e.dat"), SkipReason::Empty),
+        (PathBuf::from("f.dat"), SkipReason::TooSmall),
+        (PathBuf::from("g.dat"), SkipReason::TooLarge),
+        (PathBuf::from("h.sock"), SkipReason::NotFile),
+        (PathBuf::from("sub"), SkipReason::SameDirectory),
+    ]);
+
+    hashed.sort();
+    assert_eq!(hashed, vec![PathBuf::from("a.dat"), PathBuf::from("b.dat"), PathBuf::from("sub/i.dat")]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_copy_verified() {
+    use std::time::Duration;
+
+    let dir = test_dir("copy-verified");
+    let from = dir.join("a.dat");
+    let to = dir.join("b.dat");
+    std::fs::copy("test/random.dat", &from).unwrap();
+    let modified = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    File::options().write(true).open(&from).unwrap().set_modified(modified).unwrap();
+
+    // Copy keeps mtime and contents, original is left for the caller to remove
+    action::copy_verified(&from, &to, |a, b| std::fs::copy(a, b).map(|_| ())).unwrap();
+    assert_eq!(std::fs::read(&to).unwrap(), std::fs::read("test/random.dat").unwrap());
+    assert_eq!(std::fs::metadata(&to).unwrap().modified().unwrap(), modified);
+    assert!(from.exists());
+    std::fs::remove_file(&to).unwrap();
+
+    // Copy with different contents is removed and target is not created
+    let e = action::copy_verified(&from, &to, |_, b| std::fs::write(b, "different")).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(!to.exists());
+
+    let names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(names, vec![std::ffi::OsString::from("a.dat")]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_threads() -> Result<()> {
+    let dir = test_dir("threads");
+
+    for i in 0..20 {
+        std::fs::write(dir.join(format!("{:02}-a.dat", i)), vec![i as u8; 2048 + i]).unwrap();
+        std::fs::write(dir.join(format!("{:02}-b.dat", i)), vec![i as u8; 2048 + i]).unwrap();
+        std::fs::write(dir.join(format!("{:02}-c.dat", i)), vec![i as u8 + 1; 2048 + i]).unwrap();
+    }
+
+    let finder = DuplicateFinder::new().path(&dir).scansize(1024).sort_order(SortOrder::Filename);
+    let one = finder.clone().threads(1).run()?;
+    let many = finder.clone().threads(8).run()?;
+    let limited = finder.threads(8).readers(DeviceReaders { rotational: 1, other: 2 }).run()?;
+
+    assert_eq!(one.groups.len(), 20);
+
+    for report in [many, limited] {
+        assert_eq!(report.groups.len(), 20);
+
+        for (a, b) in one.groups.iter().zip(report.groups.iter()) {
+            assert_eq!(a.checksum, b.checksum);
+            assert_eq!(
+                a.files.iter().map(|f| &f.path).collect::<Vec<_>>(),
+                b.files.iter().map(|f| &f.path).collect::<Vec<_>>()
+            );
         }
     }
 
+    std::fs::remove_dir_all(dir).unwrap();
     Ok(())
 }
 
+#[test]
+fn test_device_disks() {
+    let mounts = "\
+23 28 0:22 / /proc rw,relatime - proc proc rw
+61 1 0:45 /@home /home rw,relatime shared:1 - btrfs /dev/sdb2 rw,ssd,subvolid=257
+";
+    assert_eq!(device::mount_source(mounts, libc::makedev(0, 45)), Some(PathBuf::from("/dev/sdb2")));
+    assert_eq!(device::mount_source(mounts, libc::makedev(0, 22)), Some(PathBuf::from("proc")));
+    assert_eq!(device::mount_source(mounts, libc::makedev(0, 46)), None);
+
+    // Partitions share the queue of their disk
+    let Ok(devices) = std::fs::read_dir("/sys/dev/block") else { return };
+
+    for e in devices.filter_map(|e| e.ok()) {
+        let name = e.file_name().into_string().unwrap();
+        let (major, minor) = name.split_once(':').unwrap();
+        let disk = device::disk(libc::makedev(major.parse().unwrap(), minor.parse().unwrap()));
+
+        assert!(!PathBuf::from(format!("/sys/dev/block/{}:{}/partition", libc::major(disk), libc::minor(disk))).exists());
+        assert_eq!(device::disk(disk), disk);
+    }
+}
+
+#[test]
+fn test_physical_order() -> Result<()> {
+    let dir = test_dir("physical");
+
+    for i in 0..10 {
+        std::fs::write(dir.join(format!("{}-a.dat", i)), vec![i as u8; 8192 + i]).unwrap();
+        std::fs::write(dir.join(format!("{}-b.dat", i)), vec![i as u8; 8192 + i]).unwrap();
+    }
+
+    let report = DuplicateFinder::new().path(&dir).scansize(4096).threads(2).physical_order(true).run()?;
+    assert_eq!(report.groups.len(), 10);
+
+    let mut items = vec!["ssd-1", "hdd-far", "unknown", "ssd-2", "hdd-near", "other-hdd"];
+    order_physical(&mut items, &[
+        None,
+        Some((8, Some(900))),
+        Some((8, None)),
+        None,
+        Some((8, Some(100))),
+        Some((9, Some(50))),
+    ]);
+    assert_eq!(items, ["ssd-1", "ssd-2", "hdd-near", "hdd-far", "unknown", "other-hdd"]);
+
+    // Blocks within the first extent of a written file are contiguous on disk
+    let f = dir.join("0-a.dat");
+    File::open(&f).unwrap().sync_all().unwrap();
+
+    if let Ok(Some(start)) = fiemap::physical_offset(&f, 0) {
+        assert_eq!(fiemap::physical_offset(&f, 100).unwrap(), Some(start + 100));
+    }
+
+    for (i, group) in report.groups.iter().enumerate() {
+        assert_eq!(group.size, 8192 + i as u64);
+        assert_eq!(group.files.len(), 2);
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_compare() -> Result<()> {
+    let dir = test_dir("compare");
+    let data: Vec<u8> = (0..2621440u32).map(|i| (i % 251) as u8).collect();
+    let mut last = data.clone();
+    *last.last_mut().unwrap() ^= 1;
+    let mut first = data.clone();
+    first[0] ^= 1;
+
+    std::fs::write(dir.join("a.dat"), &data).unwrap();
+    std::fs::write(dir.join("b.dat"), &data).unwrap();
+    std::fs::write(dir.join("c.dat"), &last).unwrap();
+    std::fs::write(dir.join("d.dat"), &last).unwrap();
+    std::fs::write(dir.join("e.dat"), &first).unwrap();
+
+    let finder = DuplicateFinder::new().path(&dir).stages(Vec::<ScanType>::new()).keep(KeepPolicy::new(vec![KeepRule::Name]));
+    let hashed = finder.clone().run()?;
+    let compared = finder.clone().compare(5).run()?;
+
+    assert_eq!(compared.groups.len(), 2);
+    assert_eq!(hashed.stages.last().unwrap().bytes_read, 5 * 2621440);
+    assert_eq!(hashed.stages.last().unwrap().bytes_saved, 0);
+    // e.dat differs in the first block, the rest of it is never read
+    assert_eq!(compared.stages.last().unwrap().bytes_read, 4 * 2621440 + 1048576);
+    assert_eq!(compared.stages.last().unwrap().bytes_saved, 2621440 - 1048576);
+
+    for (a, b) in hashed.groups.iter().zip(compared.groups.iter()) {
+        assert_eq!(a.checksum, b.checksum);
+        assert_eq!(
+            a.files.iter().map(|f| &f.path).collect::<Vec<_>>(),
+            b.files.iter().map(|f| &f.path).collect::<Vec<_>>()
+        );
+    }
+
+    // Groups larger than MAX_COMPARE are hashed, their files aren't all opened at once
+    for i in 0..=MAX_COMPARE {
+        std::fs::write(dir.join(format!("many-{}.dat", i)), [1u8; 100]).unwrap();
+    }
+
+    let mut compared = 0;
+    finder.compare(usize::MAX).run_with(|p| if let Progress::ComparingGroup { .. } = p {
+        compared += 1;
+    })?;
+    assert_eq!(compared, 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_skip_partial_stages() -> Result<()> {
+    let dir = test_dir("skip-partial");
+
+    for name in ["a.dat", "b.dat", "c.dat"] {
+        std::fs::write(dir.join(name), vec![7u8; 3072]).unwrap();
+    }
+
+    let report = DuplicateFinder::new()
+        .path(&dir)
+        .scansize(1024)
+        .stages(vec![ScanType::Last, ScanType::First])
+        .run()?;
+
+    assert_eq!(report.groups[0].files.len(), 3);
+
+    // Last bytes stage reads a third of each file, first bytes stage would make it two thirds
+    let io: Vec<(Stage, u64, u64)> = report.stages.iter().map(|s| (s.stage, s.bytes_read, s.bytes_saved)).collect();
+    assert_eq!(io, vec![
+        (Stage::Size, 0, 0),
+        (Stage::Partial(ScanType::Last), 3 * 1024, 0),
+        (Stage::Partial(ScanType::First), 0, 3 * 1024),
+        (Stage::Full, 3 * 3072, 0),
+    ]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_skipped_stage_not_covered() -> Result<()> {
+    let dir = test_dir("skip-covered");
+    let data = vec![1u8; 1572864];
+    let mut last = data.clone();
+    *last.last_mut().unwrap() = 2;
+
+    std::fs::write(dir.join("a.dat"), &data).unwrap();
+    std::fs::write(dir.join("b.dat"), &last).unwrap();
+
+    let report = DuplicateFinder::new()
+        .path(&dir)
+        .stages(parse_pipeline("size,first:1MiB,last:4KiB,full").unwrap())
+        .run()?;
+
+    // First 1 MiB would be two thirds of the files and is skipped, it doesn't count against the last 4 KiB
+    assert!(report.groups.is_empty());
+    let io: Vec<(Stage, u64, u64)> = report.stages.iter().map(|s| (s.stage, s.bytes_read, s.eliminated)).collect();
+    assert_eq!(io, vec![
+        (Stage::Size, 0, 0),
+        (Stage::Partial(ScanType::First), 0, 0),
+        (Stage::Partial(ScanType::Last), 2 * 4096, 2),
+    ]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_sampled_stages() -> Result<()> {
+    assert_eq!(ScanType::Middle.offsets(10000, 1000), vec![4500]);
+    assert_eq!(ScanType::Sampled(3).offsets(10000, 1000), vec![2000, 4500, 7000]);
+    assert_eq!(ScanType::Sampled(2).read_size(1000), 2000);
+
+    let dir = test_dir("sampled");
+    let data = vec![3u8; 65536];
+    let mut middle = data.clone();
+    middle[32768] = 4;
+
+    std::fs::write(dir.join("a.dat"), &data).unwrap();
+    std::fs::write(dir.join("b.dat"), &data).unwrap();
+    std::fs::write(dir.join("c.dat"), &middle).unwrap();
+
+    for t in [ScanType::Middle, ScanType::Sampled(3)] {
+        let report = DuplicateFinder::new()
+            .path(&dir)
+            .scansize(1024)
+            .stages(vec![ScanType::Last, ScanType::First, t])
+            .run()?;
+
+        // Same first and last bytes, the difference is found before full hashing
+        let files: Vec<(Stage, u64)> = report.stages.iter().map(|s| (s.stage, s.file_count)).collect();
+        assert_eq!(files, vec![
+            (Stage::Size, 3),
+            (Stage::Partial(ScanType::Last), 3),
+            (Stage::Partial(ScanType::First), 3),
+            (Stage::Partial(t), 2),
+            (Stage::Full, 2),
+        ]);
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_pipeline() -> Result<()> {
+    let stages = parse_pipeline("size,first:4KiB,last,middle:1KiB,sample:8x64KiB,sample:2,full").unwrap();
+    assert_eq!(stages, vec![
+        PartialStage { scan: ScanType::First, size: Some(4096) },
+        PartialStage { scan: ScanType::Last, size: None },
+        PartialStage { scan: ScanType::Middle, size: Some(1024) },
+        PartialStage { scan: ScanType::Sampled(8), size: Some(65536) },
+        PartialStage { scan: ScanType::Sampled(2), size: None },
+    ]);
+    assert_eq!(stages.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+               vec!["first:4096", "last", "middle:1024", "sample:8x65536", "sample:2"]);
+    assert_eq!(parse_pipeline("size,full").unwrap(), vec![]);
+
+    for s in ["first,full", "size,first", "size,full,full", "size,size,full", "size,first:0,full",
+        "size,sample,full", "size,sample:0,full", "size,sample:2x,full", "size,bogus,full"] {
+        assert!(parse_pipeline(s).is_err(), "{}", s);
+    }
+
+    let dir = test_dir("pipeline");
+    let data = vec![5u8; 65536];
+    let mut first = data.clone();
+    first[0] = 6;
+    let mut last = data.clone();
+    last[65535] = 6;
+
+    std::fs::write(dir.join("a.dat"), &data).unwrap();
+    std::fs::write(dir.join("b.dat"), &data).unwrap();
+    std::fs::write(dir.join("c.dat"), &first).unwrap();
+    std::fs::write(dir.join("d.dat"), &last).unwrap();
+    std::fs::write(dir.join("e.dat"), vec![5u8; 100]).unwrap();
+
+    let report = DuplicateFinder::new()
+        .path(&dir)
+        .stages(parse_pipeline("size,first:1KiB,last:2KiB,full").unwrap())
+        .run()?;
+
+    assert_eq!(report.groups[0].files.len(), 2);
+
+    let stats: Vec<(Stage, u64, u64)> = report.stages.iter().map(|s| (s.stage, s.eliminated, s.bytes_read)).collect();
+    assert_eq!(stats, vec![
+        (Stage::Size, 1, 0),
+        (Stage::Partial(ScanType::First), 1, 4 * 1024),
+        (Stage::Partial(ScanType::Last), 1, 3 * 2048),
+        (Stage::Full, 0, 2 * 65536),
+    ]);
+
+    let r = DuplicateFinder::new()
+        .path(&dir)
+        .stages(vec![PartialStage { scan: ScanType::First, size: Some(0) }])
+        .run();
+    assert!(matches!(r, Err(Error::ZeroScanSize)));
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_adaptive_scansize() -> Result<()> {
+    assert_eq!(adaptive_scansize(1000, 2), 4096);
+    assert_eq!(adaptive_scansize(1153434, 2), 20480);
+    assert_eq!(adaptive_scansize(1153434, 1000), 4096);
+    assert_eq!(adaptive_scansize(64 * 1048576, 2), 1048576);
+    assert_eq!(adaptive_scansize(64 * 1048576, 4), 524288);
+    assert_eq!(adaptive_scansize(50 << 30, 2), 64 * 1048576);
+
+    let dir = test_dir("adaptive");
+    let data = vec![9u8; 524288];
+    let mut middle = data.clone();
+    middle[262144] = 8;
+
+    std::fs::write(dir.join("a.dat"), &data).unwrap();
+    std::fs::write(dir.join("b.dat"), &data).unwrap();
+    std::fs::write(dir.join("c.dat"), &middle).unwrap();
+
+    let mut scanned: Vec<(ScanType, u64, u64)> = Vec::new();
+    let report = DuplicateFinder::new()
+        .path(&dir)
+        .adaptive_scansize(true)
+        .stages(vec![ScanType::Last.into(), PartialStage { scan: ScanType::First, size: Some(1024) }])
+        .run_with(|p| if let Progress::ScanningGroup { scan, file_count, scansize, .. } = p {
+            scanned.push((*scan, *file_count, *scansize));
+        })?;
+
+    // With the default 1 MiB scan size partial stages would be skipped for these files
+    assert_eq!(report.groups[0].files.len(), 2);
+    assert_eq!(scanned, vec![(ScanType::Last, 3, 4096), (ScanType::First, 3, 1024)]);
+    assert_eq!(report.stages[1].bytes_read, 3 * 4096);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+// Needs a directory on btrfs or XFS, for example a loopback image:
+// Temporary directory is expected to be on a file system without reflinks, such as ext4 or tmpfs
+#[test]
+fn test_reflink_unsupported() -> Result<()> {
+    let dir = test_dir("reflink-unsupported");
+    std::fs::copy("test/random.dat", dir.join("a.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("b.dat")).unwrap();
+    let before = std::fs::metadata(dir.join("b.dat")).unwrap();
+
+    let report = DuplicateFinder::new().path(&dir).run()?;
+    let group = &report.groups[0];
+    let duplicate = &group.duplicates()[0];
+
+    match Action::Reflink.apply(group.keep(), duplicate) {
+        Err(Error::Unsupported { path, action }) => {
+            assert_eq!(path, duplicate.path);
+            assert_eq!(action, Action::Reflink);
+        }
+        r => panic!("expected unsupported, got {:?}", r),
+    }
+
+    let after = std::fs::metadata(&duplicate.path).unwrap();
+    assert_eq!(before.ino(), after.ino());
+    assert_eq!(std::fs::read(&duplicate.path).unwrap(), std::fs::read("test/random.dat").unwrap());
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
+
+// SAMANLAINEN_REFLINK_DIR=/mnt/btrfs cargo test -- --ignored
+#[test]
+#[ignore]
+fn test_reflink() -> Result<()> {
+    let dir = PathBuf::from(std::env::var("SAMANLAINEN_REFLINK_DIR").unwrap()).join("samanlainen-reflink");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy("test/random.dat", dir.join("a.dat")).unwrap();
+    std::fs::copy("test/random.dat", dir.join("b.dat")).unwrap();
+
+    let report = DuplicateFinder::new().path(&dir).run()?;
+    let group = &report.groups[0];
+    Action::Reflink.apply(group.keep(), &group.duplicates()[0])?;
+
+    let a = std::fs::metadata(dir.join("a.dat")).unwrap();
+    let b = std::fs::metadata(dir.join("b.dat")).unwrap();
+    assert_ne!(a.ino(), b.ino());
+    assert_eq!(std::fs::read(dir.join("b.dat")).unwrap(), std::fs::read("test/random.dat").unwrap());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    Ok(())
+}
@@ -0,0 +1,168 @@
+// Worker pool for hashing files concurrently
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+
+use crate::Result;
+
+// Number of worker threads to use, 0 means one per CPU core
+pub(crate) fn thread_count(threads: usize) -> usize {
+    match threads {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => n,
+    }
+}
+
+// Work items waiting for a worker, per device
+struct Queue {
+    // Indices of waiting items of each device, in item order
+    waiting: Vec<VecDeque<usize>>,
+    // Items of each device being worked on
+    active: Vec<usize>,
+    // Maximum number of active items of each device, 0 means no limit
+    limits: Vec<usize>,
+    // Sink failed, workers stop
+    stopped: bool,
+}
+
+enum Next {
+    Item(usize, usize),
+    Wait,
+    Done,
+}
+
+impl Queue {
+    // Earliest waiting item of a device which isn't at its limit
+    fn next(&mut self) -> Next {
+        if self.stopped {
+            return Next::Done;
+        }
+
+        let mut next: Option<(usize, usize)> = None;
+        let mut waiting = false;
+
+        for (dev, items) in self.waiting.iter().enumerate() {
+            let Some(&i) = items.front() else { continue };
+            waiting = true;
+
+            if self.limits[dev] != 0 && self.active[dev] >= self.limits[dev] {
+                continue;
+            }
+
+            if next.is_none_or(|(_, j)| i < j) {
+                next = Some((dev, i));
+            }
+        }
+
+        match next {
+            Some((dev, i)) => {
+                self.waiting[dev].pop_front();
+                self.active[dev] += 1;
+                Next::Item(dev, i)
+            }
+            None if waiting => Next::Wait,
+            None => Next::Done,
+        }
+    }
+}
+
+// Run work for each item on worker threads and pass the results to sink in item order,
+// so the output doesn't depend on which thread finished first.
+// Items are read from devices given by device(), with at most the device's reader limit of items
+// worked on at the same time. Workers stop when sink returns an error.
+pub(crate) fn run<T, R, D, W, S>(
+    threads: usize, // number of worker threads, see thread_count
+    items: &[T], // work items
+    mut device: D, // device of item and its reader limit, 0 means no limit
+    work: W, // run on worker threads
+    mut sink: S, // run on calling thread
+) -> Result<()>
+    where T: Sync, R: Send, D: FnMut(&T) -> (u64, usize), W: Fn(&T) -> R + Sync, S: FnMut(usize, R) -> Result<()> {
+    let threads = thread_count(threads).min(items.len());
+
+    if threads <= 1 {
+        for (i, item) in items.iter().enumerate() {
+            sink(i, work(item))?;
+        }
+
+        return Ok(());
+    }
+
+    let mut queue = Queue {
+        waiting: Vec::new(),
+        active: Vec::new(),
+        limits: Vec::new(),
+        stopped: false,
+    };
+
+    let mut devices: HashMap<u64, usize> = HashMap::new();
+
+    for (i, item) in items.iter().enumerate() {
+        let (dev, limit) = device(item);
+        let d = *devices.entry(dev).or_insert_with(|| {
+            queue.waiting.push(VecDeque::new());
+            queue.active.push(0);
+            queue.limits.push(limit);
+            queue.waiting.len() - 1
+        });
+
+        queue.waiting[d].push_back(i);
+    }
+
+    let queue = Mutex::new(queue);
+    let ready = Condvar::new();
+
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+
+        for _ in 0..threads {
+            let tx = tx.clone();
+            let (queue, ready, work) = (&queue, &ready, &work);
+
+            scope.spawn(move || {
+                let mut q = queue.lock().unwrap();
+
+                loop {
+                    match q.next() {
+                        Next::Item(dev, i) => {
+                            drop(q);
+                            let sent = tx.send((i, work(&items[i])));
+                            q = queue.lock().unwrap();
+
+                            q.active[dev] -= 1;
+                            ready.notify_all();
+
+                            if sent.is_err() {
+                                break;
+                            }
+                        }
+                        Next::Wait => q = ready.wait(q).unwrap(),
+                        Next::Done => break,
+                    }
+                }
+            });
+        }
+
+        drop(tx);
+
+        // Results which arrived before the ones preceding them
+        let mut pending: HashMap<usize, R> = HashMap::new();
+        let mut expected = 0;
+
+        for (i, r) in rx {
+            pending.insert(i, r);
+
+            while let Some(r) = pending.remove(&expected) {
+                if let Err(e) = sink(expected, r) {
+                    queue.lock().unwrap().stopped = true;
+                    ready.notify_all();
+                    return Err(e);
+                }
+
+                expected += 1;
+            }
+        }
+
+        Ok(())
+    })
+}
@@ -0,0 +1,125 @@
+// Copy-on-write clone and deduplication using ioctls (btrfs, XFS, ..)
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// Share the extents of src with dest, the kernel verifies that the contents are identical
+#[cfg(target_os = "linux")]
+pub(crate) fn dedupe(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+
+    // _IOWR(0x94, 54, struct file_dedupe_range)
+    const FIDEDUPERANGE: u32 = 0xC0189436;
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+    // Largest range asked per call, file systems may cap it further
+    const CHUNK: u64 = 16 * 1024 * 1024;
+
+    #[repr(C)]
+    struct FileDedupeRangeInfo {
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    // struct file_dedupe_range with one destination
+    #[repr(C)]
+    struct FileDedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        info: FileDedupeRangeInfo,
+    }
+
+    let s = File::open(src)?;
+    let d = OpenOptions::new().read(true).write(true).open(dest)?;
+
+    let len = s.metadata()?.len();
+    if d.metadata()?.len() != len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "file sizes differ"));
+    }
+
+    let mut offset: u64 = 0;
+
+    while offset < len {
+        let mut r = FileDedupeRange {
+            src_offset: offset,
+            src_length: (len - offset).min(CHUNK),
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: FileDedupeRangeInfo {
+                dest_fd: d.as_raw_fd() as i64,
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            },
+        };
+
+        if unsafe { libc::ioctl(s.as_raw_fd(), FIDEDUPERANGE as libc::Ioctl, &mut r) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if r.info.status < 0 {
+            return Err(io::Error::from_raw_os_error(-r.info.status));
+        }
+
+        if r.info.status == FILE_DEDUPE_RANGE_DIFFERS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "file contents differ"));
+        }
+
+        if r.info.bytes_deduped == 0 {
+            return Err(io::Error::other("no bytes deduplicated"));
+        }
+
+        offset += r.info.bytes_deduped;
+    }
+
+    Ok(())
+}
+
+// Create new file dest sharing the extents of src
+#[cfg(target_os = "linux")]
+pub(crate) fn clone(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::fs::{remove_file, OpenOptions};
+    use std::os::unix::io::AsRawFd;
+
+    let s = File::open(src)?;
+    let d = OpenOptions::new().write(true).create_new(true).open(dest)?;
+
+    if unsafe { libc::ioctl(d.as_raw_fd(), libc::FICLONE, s.as_raw_fd()) } < 0 {
+        let e = io::Error::last_os_error();
+        drop(d);
+        let _ = remove_file(dest);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn dedupe(_src: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn clone(_src: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// File system (or kernel) can't share extents between the files
+pub(crate) fn is_unsupported(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL) | Some(libc::EXDEV) | Some(libc::ENOSYS)
+    )
+}
@@ -0,0 +1,108 @@
+// Compact in-memory table of scanned files
+//
+// Directory paths are stored once and files refer to them by index, so a file costs its name
+// and a few numbers instead of a full path. Pipeline stages pass file indices around.
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{FileEntry, FileId};
+
+pub(crate) type FileIndex = u32;
+
+// Candidate files grouped by file size
+pub(crate) type Candidates = HashMap<u64, Vec<FileIndex>>;
+
+// Hashed files grouped by file size and checksum, sorted by size and checksum
+pub(crate) type Checksums = Vec<((u64, String), Vec<FileIndex>)>;
+
+struct FileRecord {
+    dir: u32,
+    name: Box<OsStr>,
+    root: u32,
+    depth: u32,
+    id: FileId,
+    size: u64,
+    modified: SystemTime,
+    links: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct FileTable {
+    dirs: Vec<PathBuf>,
+    files: Vec<FileRecord>,
+}
+
+impl FileTable {
+    pub(crate) fn add_dir(&mut self, path: &Path) -> u32 {
+        self.dirs.push(path.to_path_buf());
+        (self.dirs.len() - 1) as u32
+    }
+
+    pub(crate) fn add_file(
+        &mut self,
+        dir: u32, // index of parent directory
+        name: &OsStr, // file name
+        root: usize, // index of scanned path
+        depth: usize, // depth below scanned path
+        m: &Metadata,
+    ) -> FileIndex {
+        self.files.push(FileRecord {
+            dir,
+            name: name.into(),
+            root: root as u32,
+            depth: depth as u32,
+            id: FileId::from_metadata(m),
+            size: m.len(),
+            modified: m.modified().unwrap_or(UNIX_EPOCH),
+            links: m.nlink(),
+        });
+
+        (self.files.len() - 1) as FileIndex
+    }
+
+    // Number of files in the table
+    pub(crate) fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub(crate) fn path(&self, i: FileIndex) -> PathBuf {
+        let f = &self.files[i as usize];
+        self.dirs[f.dir as usize].join(&*f.name)
+    }
+
+    pub(crate) fn id(&self, i: FileIndex) -> FileId {
+        self.files[i as usize].id
+    }
+
+    pub(crate) fn entry(&self, i: FileIndex) -> FileEntry {
+        let f = &self.files[i as usize];
+
+        FileEntry {
+            path: self.path(i),
+            id: f.id,
+            size: f.size,
+            root: f.root as usize,
+            depth: f.depth as usize,
+            modified: f.modified,
+            links: f.links,
+        }
+    }
+
+    // Drop files which are not candidates anymore, indices in candidates are updated
+    pub(crate) fn retain(&mut self, candidates: &mut Candidates) {
+        let mut old: Vec<Option<FileRecord>> = std::mem::take(&mut self.files).into_iter().map(Some).collect();
+
+        for files in candidates.values_mut() {
+            for i in files.iter_mut() {
+                if let Some(f) = old[*i as usize].take() {
+                    self.files.push(f);
+                    *i = (self.files.len() - 1) as FileIndex;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// Freedesktop.org trash, see https://specifications.freedesktop.org/trash-spec/trashspec-latest.html
+use std::env;
+use std::ffi::OsString;
+use std::fs::{create_dir_all, metadata, remove_file, rename, symlink_metadata, DirBuilder, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+// Move file into the trash on the same file system, returns the new location of the file
+pub(crate) fn trash(path: &Path) -> io::Result<PathBuf> {
+    let dev = metadata(path)?.dev();
+
+    // Home trash is used for files on the same file system as the home directory
+    if let Some(dir) = home_trash() {
+        if let Some(existing) = dir.ancestors().find(|p| p.exists()) {
+            if metadata(existing)?.dev() == dev {
+                create_dir(&dir)?;
+                return trash_into(&dir, path, None);
+            }
+        }
+    }
+
+    // Otherwise trash directory in the top directory of the file system
+    let top = top_dir(path, dev)?;
+    let uid = unsafe { libc::getuid() };
+
+    let admin = top.join(".Trash");
+    if let Ok(m) = symlink_metadata(&admin) {
+        if m.is_dir() && m.mode() & 0o1000 != 0 {
+            let dir = admin.join(uid.to_string());
+            if create_dir(&dir).is_ok() {
+                return trash_into(&dir, path, Some(&top));
+            }
+        }
+    }
+
+    let dir = top.join(format!(".Trash-{}", uid));
+    create_dir(&dir)?;
+    trash_into(&dir, path, Some(&top))
+}
+
+fn home_trash() -> Option<PathBuf> {
+    match env::var_os("XDG_DATA_HOME") {
+        Some(d) if !d.is_empty() => Some(PathBuf::from(d).join("Trash")),
+        _ => env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/share/Trash")),
+    }
+}
+
+// Trash directories must be private to the user
+fn create_dir(dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
+
+// Mount point of the file system containing path
+fn top_dir(path: &Path, dev: u64) -> io::Result<PathBuf> {
+    let path = path.canonicalize()?;
+    let mut top = path.as_path();
+
+    while let Some(parent) = top.parent() {
+        if metadata(parent)?.dev() != dev {
+            break;
+        }
+
+        top = parent;
+    }
+
+    Ok(top.to_path_buf())
+}
+
+// Write .trashinfo with an unique name and move file under that name, top is set for per volume trash
+fn trash_into(dir: &Path, path: &Path, top: Option<&Path>) -> io::Result<PathBuf> {
+    let files = dir.join("files");
+    let info = dir.join("info");
+    create_dir_all(&files)?;
+    create_dir_all(&info)?;
+
+    let path = path.canonicalize()?;
+    let original = match top {
+        // Paths in per volume trash are relative to the top directory
+        Some(top) => path.strip_prefix(top).unwrap_or(&path).to_path_buf(),
+        None => path.clone(),
+    };
+    let name = path.file_name().unwrap_or_default();
+
+    for i in 1u32.. {
+        let mut n = OsString::from(name);
+        if i > 1 {
+            n.push(format!(".{}", i));
+        }
+
+        let mut info_name = n.clone();
+        info_name.push(".trashinfo");
+        let info_path = info.join(info_name);
+
+        // Reserve the name by creating the info file exclusively
+        let mut f = match OpenOptions::new().write(true).create_new(true).open(&info_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+
+        let destination = files.join(&n);
+        if symlink_metadata(&destination).is_ok() {
+            drop(f);
+            remove_file(&info_path)?;
+            continue;
+        }
+
+        let r = write!(
+            f,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            url_encode(original.as_os_str().as_bytes()),
+            local_time()
+        )
+            .and_then(|_| f.sync_all())
+            .and_then(|_| rename(&path, &destination));
+
+        if let Err(e) = r {
+            drop(f);
+            let _ = remove_file(&info_path);
+            return Err(e);
+        }
+
+        return Ok(destination);
+    }
+
+    Err(io::Error::other("no free name in trash"))
+}
+
+// Remove the .trashinfo of a file which is not in the trash anymore
+pub(crate) fn remove_info(trashed: &Path) -> io::Result<()> {
+    let (dir, name) = match (trashed.parent().and_then(|p| p.parent()), trashed.file_name()) {
+        (Some(d), Some(n)) => (d, n),
+        _ => return Ok(()),
+    };
+
+    let mut info_name = OsString::from(name);
+    info_name.push(".trashinfo");
+
+    match remove_file(dir.join("info").join(info_name)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn url_encode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            s.push(b as char);
+        } else {
+            s.push_str(&format!("%{:02X}", b));
+        }
+    }
+
+    s
+}
+
+// Current local time as YYYY-MM-DDThh:mm:ss
+fn local_time() -> String {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}